@@ -0,0 +1,198 @@
+// Copyright 2017 Mike Lubinets <lubinetsm@yandex.ru>.
+// See the COPYRIGHT file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Requests sent by the server to the client, such as `workspace/applyEdit`,
+//! `window/showMessageRequest` or `client/registerCapability`.
+
+use actions::InitActionContext;
+use lsp_data::RequestMessage;
+use server::Output;
+use server::panic::catch_panic;
+use jsonrpc;
+use json;
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long the client has to answer a request before we give up on it.
+pub const CLIENT_REQUEST_TIMEOUT_SECS: u64 = 30;
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// The client answered with an error.
+    Failed(jsonrpc::Error),
+    /// No answer in time.
+    TimedOut,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ClientError::Failed(ref e) => write!(f, "{} ({:?})", e.message, e.code),
+            ClientError::TimedOut => write!(f, "timed out"),
+        }
+    }
+}
+
+pub type ClientResult = Result<json::Value, ClientError>;
+
+/// Called on the main thread with the answer of the client.
+trait Callback: Send {
+    fn call(self: Box<Self>, ctx: &InitActionContext, result: ClientResult);
+}
+
+impl<F: FnOnce(&InitActionContext, ClientResult) + Send> Callback for F {
+    fn call(self: Box<F>, ctx: &InitActionContext, result: ClientResult) {
+        (*self)(ctx, result)
+    }
+}
+
+struct Pending {
+    method: String,
+    deadline: Instant,
+    callback: Box<Callback>,
+}
+
+/// Requests sent to the client that haven't been answered yet.
+#[derive(Clone, Default)]
+pub struct ClientRequests {
+    pending: Arc<Mutex<HashMap<u64, Pending>>>,
+}
+
+impl ClientRequests {
+    pub fn new() -> ClientRequests {
+        ClientRequests::default()
+    }
+
+    /// Sends request `method` to the client, `callback` is called with the
+    /// answer, or with `ClientError::TimedOut` if there is none in time.
+    pub fn send<O, P, F>(&self, out: &O, method: &str, params: P, callback: F)
+    where
+        O: Output,
+        P: Serialize + Debug,
+        F: FnOnce(&InitActionContext, ClientResult) + Send + 'static,
+    {
+        let id = out.provide_id();
+        let timeout = Duration::from_secs(CLIENT_REQUEST_TIMEOUT_SECS);
+        self.register(id as u64, method, timeout, callback);
+
+        let request = RequestMessage::new(id, method.to_owned(), params);
+        out.response(json::to_string(&request).unwrap());
+    }
+
+    fn register<F>(&self, id: u64, method: &str, timeout: Duration, callback: F)
+    where
+        F: FnOnce(&InitActionContext, ClientResult) + Send + 'static,
+    {
+        let pending = Pending {
+            method: method.to_owned(),
+            deadline: Instant::now() + timeout,
+            callback: Box::new(callback),
+        };
+        if let Some(old) = self.pending.lock().unwrap().insert(id, pending) {
+            debug!("Request {} ({}) replaced before it was answered", id, old.method);
+        }
+    }
+
+    /// Passes the answer to request `id` to its callback.
+    pub fn complete(&self, ctx: &InitActionContext, id: u64, result: ClientResult) {
+        // Don't hold the lock in the callback, it may send more requests.
+        let pending = self.pending.lock().unwrap().remove(&id);
+        match pending {
+            Some(pending) => {
+                trace!("Client answered {} ({})", id, pending.method);
+                pending.callback.call(ctx, result);
+            }
+            None => debug!("Unexpected response {}", id),
+        }
+    }
+
+    /// Fails the requests that the client didn't answer in time.
+    pub fn expire(&self, ctx: &InitActionContext, now: Instant) {
+        let expired: Vec<(u64, Pending)> = {
+            let mut pending = self.pending.lock().unwrap();
+            let ids: Vec<u64> = pending
+                .iter()
+                .filter(|&(_, p)| p.deadline <= now)
+                .map(|(id, _)| *id)
+                .collect();
+            ids.into_iter()
+                .map(|id| (id, pending.remove(&id).unwrap()))
+                .collect()
+        };
+
+        for (id, pending) in expired {
+            debug!("Client didn't answer {} ({}) in time", id, pending.method);
+            let Pending { method, callback, .. } = pending;
+            // Like a response, a failing callback must not stop the others.
+            if let Err(message) = catch_panic(|| callback.call(ctx, Err(ClientError::TimedOut))) {
+                debug!("Timeout of request {} ({}) panicked: {}", id, method, message);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use lsp_data::ClientFeatures;
+    use vfs::Vfs;
+
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_complete_and_expire() {
+        let ctx = InitActionContext::new(Arc::new(Vfs::new()), vec![], ClientFeatures::default());
+        let requests = ClientRequests::new();
+        let (sender, receiver) = mpsc::channel();
+
+        for &(id, timeout) in &[(1, 0), (2, 60), (3, 60)] {
+            let sender = sender.clone();
+            requests.register(id, "test", Duration::from_secs(timeout), move |_, result| {
+                sender.send((id, format!("{:?}", result))).unwrap()
+            });
+        }
+
+        requests.complete(&ctx, 2, Ok(json!(true)));
+        requests.complete(&ctx, 2, Ok(json!(false)));
+        requests.expire(&ctx, Instant::now());
+
+        let answers: Vec<(u64, String)> = receiver.try_iter().collect();
+        assert_eq!(
+            answers,
+            vec![(2, "Ok(Bool(true))".to_owned()), (1, "Err(TimedOut)".to_owned())]
+        );
+        assert_eq!(requests.pending.lock().unwrap().keys().collect::<Vec<_>>(), vec![&3]);
+    }
+
+    #[test]
+    fn test_expire_panicking_callback() {
+        let ctx = InitActionContext::new(Arc::new(Vfs::new()), vec![], ClientFeatures::default());
+        let requests = ClientRequests::new();
+        let (sender, receiver) = mpsc::channel();
+
+        for id in 1..3 {
+            let sender = sender.clone();
+            requests.register(id, "test", Duration::from_secs(0), move |_, _| {
+                sender.send(id).unwrap();
+                panic!("timeout callback");
+            });
+        }
+        requests.expire(&ctx, Instant::now());
+
+        let mut called: Vec<u64> = receiver.try_iter().collect();
+        called.sort();
+        assert_eq!(called, vec![1, 2]);
+        assert!(requests.pending.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,253 @@
+// Copyright 2017 Mike Lubinets <lubinetsm@yandex.ru>.
+// See the COPYRIGHT file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Delays the work following an edit until the file stops changing, so that
+//! a burst of keystrokes is analyzed once, in its final state.
+
+use server::panic::catch_panic;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+trait Job: Send {
+    fn call_box(self: Box<Self>, run: &Run);
+}
+
+impl<F: FnOnce(&Run) + Send> Job for F {
+    fn call_box(self: Box<F>, run: &Run) {
+        (*self)(run)
+    }
+}
+
+struct Scheduled {
+    deadline: Instant,
+    run: Run,
+    job: Box<Job>,
+}
+
+enum Message {
+    Schedule(Scheduled),
+    Stop,
+}
+
+/// Last job scheduled for every file, jobs for other generations are superseded.
+type Generations = Arc<Mutex<HashMap<PathBuf, u64>>>;
+
+/// A job about to run, or running.
+pub struct Run {
+    file_path: PathBuf,
+    generation: u64,
+    generations: Generations,
+}
+
+impl Run {
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
+
+    /// Whether a job was scheduled for the same file since this one, or it
+    /// was cancelled. Its results are outdated then.
+    pub fn is_superseded(&self) -> bool {
+        let generations = self.generations.lock().unwrap();
+        generations.get(&self.file_path) != Some(&self.generation)
+    }
+
+    /// Calls `f` unless the job is superseded. A job scheduled or cancelled
+    /// meanwhile waits for `f`, so it sees the results published by `f`
+    /// before anything it does afterwards. Returns whether `f` was called.
+    pub fn unless_superseded<F: FnOnce()>(&self, f: F) -> bool {
+        let generations = self.generations.lock().unwrap();
+        if generations.get(&self.file_path) != Some(&self.generation) {
+            return false;
+        }
+        f();
+        true
+    }
+}
+
+/// Runs the jobs scheduled for a file on a separate thread, once no other
+/// job was scheduled for the file for a while.
+#[derive(Clone)]
+pub struct Debouncer {
+    jobs: Arc<Mutex<mpsc::Sender<Message>>>,
+    generations: Generations,
+    /// Taken by `stop`.
+    thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl Debouncer {
+    pub fn new() -> Debouncer {
+        let (jobs, receiver) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("debounce".to_owned())
+            .spawn(move || run_jobs(receiver))
+            .expect("Couldn't spawn the debounce thread");
+
+        Debouncer {
+            jobs: Arc::new(Mutex::new(jobs)),
+            generations: Arc::new(Mutex::new(HashMap::new())),
+            thread: Arc::new(Mutex::new(Some(thread))),
+        }
+    }
+
+    /// Runs `job` after `delay`, unless another job is scheduled for
+    /// `file_path` in the meantime, which then replaces it.
+    pub fn schedule<F>(&self, file_path: &Path, delay: Duration, job: F)
+    where
+        F: FnOnce(&Run) + Send + 'static,
+    {
+        let run = Run {
+            file_path: file_path.to_owned(),
+            generation: self.supersede(file_path),
+            generations: self.generations.clone(),
+        };
+        let scheduled = Scheduled {
+            deadline: Instant::now() + delay,
+            run,
+            job: Box::new(job),
+        };
+        // Dropped if the thread is stopped.
+        let _ = self.jobs.lock().unwrap().send(Message::Schedule(scheduled));
+    }
+
+    /// Drops the job scheduled for `file_path`, a running one sees that it
+    /// is superseded.
+    pub fn cancel(&self, file_path: &Path) {
+        self.supersede(file_path);
+    }
+
+    /// Drops the scheduled jobs and waits for the running one, whose results
+    /// are seen as superseded. Jobs scheduled afterwards never run.
+    pub fn stop(&self) {
+        for generation in self.generations.lock().unwrap().values_mut() {
+            *generation += 1;
+        }
+        let _ = self.jobs.lock().unwrap().send(Message::Stop);
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            if thread.join().is_err() {
+                debug!("debounce: the thread panicked");
+            }
+        }
+    }
+
+    fn supersede(&self, file_path: &Path) -> u64 {
+        let mut generations = self.generations.lock().unwrap();
+        let generation = generations.entry(file_path.to_owned()).or_insert(0);
+        *generation += 1;
+        *generation
+    }
+}
+
+fn run_jobs(receiver: mpsc::Receiver<Message>) {
+    let mut scheduled: HashMap<PathBuf, Scheduled> = HashMap::new();
+    loop {
+        let received = match scheduled.values().map(|s| s.deadline).min() {
+            Some(deadline) => {
+                let now = Instant::now();
+                let timeout = if deadline > now { deadline - now } else { Duration::from_secs(0) };
+                receiver.recv_timeout(timeout)
+            }
+            None => receiver.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(Message::Schedule(job)) => {
+                // Coalesced with the job it replaces, if any.
+                scheduled.insert(job.run.file_path.clone(), job);
+                continue;
+            }
+            Ok(Message::Stop) | Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+        }
+
+        let now = Instant::now();
+        let due: Vec<PathBuf> = scheduled
+            .iter()
+            .filter(|&(_, job)| job.deadline <= now)
+            .map(|(file_path, _)| file_path.clone())
+            .collect();
+        for file_path in due {
+            let Scheduled { run, job, .. } = scheduled.remove(&file_path).unwrap();
+            if run.is_superseded() {
+                trace!("debounce: dropping superseded job for {:?}", file_path);
+                continue;
+            }
+            if let Err(message) = catch_panic(|| job.call_box(&run)) {
+                debug!("debounce: job for {:?} panicked: {}", file_path, message);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_schedule() {
+        let debouncer = Debouncer::new();
+        let (sender, receiver) = mpsc::channel();
+        let delay = Duration::from_millis(50);
+
+        let a = Path::new("a.slang");
+        let b = Path::new("b.slang");
+        for i in 0..5 {
+            let sender = sender.clone();
+            debouncer.schedule(a, delay, move |_: &Run| sender.send(("a", i)).unwrap());
+        }
+        let sender_ = sender.clone();
+        debouncer.schedule(b, delay, move |_: &Run| sender_.send(("b", 0)).unwrap());
+        debouncer.schedule(b, delay, move |_: &Run| sender.send(("b", 1)).unwrap());
+        debouncer.cancel(b);
+
+        let timeout = Duration::from_secs(5);
+        assert_eq!(receiver.recv_timeout(timeout), Ok(("a", 4)));
+        assert!(receiver.recv_timeout(delay * 4).is_err());
+    }
+
+    #[test]
+    fn test_cancel_waits_for_publishing() {
+        let debouncer = Debouncer::new();
+        let (sender, receiver) = mpsc::channel();
+        let path = Path::new("a.slang");
+
+        let sender_ = sender.clone();
+        debouncer.schedule(path, Duration::from_millis(0), move |run: &Run| {
+            run.unless_superseded(|| {
+                sender_.send("publishing").unwrap();
+                thread::sleep(Duration::from_millis(100));
+                sender_.send("published").unwrap();
+            });
+        });
+
+        let timeout = Duration::from_secs(5);
+        assert_eq!(receiver.recv_timeout(timeout), Ok("publishing"));
+        debouncer.cancel(path);
+        sender.send("cancelled").unwrap();
+        assert_eq!(receiver.recv_timeout(timeout), Ok("published"));
+        assert_eq!(receiver.recv_timeout(timeout), Ok("cancelled"));
+    }
+
+    #[test]
+    fn test_stop() {
+        let debouncer = Debouncer::new();
+        let (sender, receiver) = mpsc::channel();
+        let delay = Duration::from_millis(50);
+
+        let sender_ = sender.clone();
+        debouncer.schedule(Path::new("a.slang"), delay, move |_: &Run| sender_.send("a").unwrap());
+        debouncer.stop();
+        debouncer.schedule(Path::new("b.slang"), delay, move |_: &Run| sender.send("b").unwrap());
+
+        assert!(receiver.recv_timeout(delay * 4).is_err());
+    }
+}
@@ -0,0 +1,232 @@
+// Copyright 2017 Mike Lubinets <lubinetsm@yandex.ru>.
+// See the COPYRIGHT file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Source formatting helpers for Slang documents.
+
+use lsp_data::*;
+use syntax::{self, Line};
+
+/// Typing a newline after a block header opens the block body.
+pub const TRIGGER_NEWLINE: &str = "\n";
+/// Typing the last character of `end` re-indents the closing line.
+pub const TRIGGER_END: &str = "d";
+
+/// Computes the edits for `textDocument/onTypeFormatting`.
+///
+/// `position` is the cursor position after `ch` has been inserted by the client.
+pub fn on_type_formatting(
+    text: &str,
+    position: Position,
+    ch: &str,
+    options: &FormattingOptions,
+) -> Vec<TextEdit> {
+    let lines: Vec<&str> = text.split('\n')
+        .map(|line| line.trim_right_matches('\r'))
+        .collect();
+
+    let row = position.line as usize;
+    if row >= lines.len() {
+        return vec![];
+    }
+
+    match ch {
+        TRIGGER_NEWLINE => open_block(&lines, row, options),
+        TRIGGER_END => close_block(&lines, row, position.character as usize),
+        _ => vec![],
+    }
+}
+
+/// Indents the freshly inserted line if the line above is a block header
+/// and closes the block with a matching `end name` unless it is closed already.
+fn open_block(lines: &[&str], row: usize, options: &FormattingOptions) -> Vec<TextEdit> {
+    if row == 0 {
+        return vec![];
+    }
+
+    let header = lines[row - 1];
+    let name = match syntax::classify(header) {
+        Line::Header(name) => name,
+        _ => return vec![],
+    };
+    let indent = syntax::indentation(header);
+
+    let unit = if options.insert_spaces {
+        " ".repeat(options.tab_size as usize)
+    } else {
+        "\t".to_owned()
+    };
+
+    let current = lines[row];
+    let mut new_text = format!("{}{}{}", indent, unit, current.trim_left());
+
+    // Only an `end` aligned with the header closes it, a less indented one
+    // closes an enclosing block.
+    let closed = lines[row + 1..]
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .find(|line| syntax::indentation(line).len() <= indent.len())
+        .map_or(false, |line| match syntax::classify(line) {
+            Line::End(end) => {
+                syntax::indentation(line) == indent && end.map_or(true, |end| end == name)
+            }
+            _ => false,
+        });
+    if !closed {
+        new_text.push_str(&format!("\n{}end {}", indent, name));
+    }
+
+    vec![
+        TextEdit::new(
+            Range::new(
+                Position::new(row as u64, 0),
+                Position::new(row as u64, current.chars().count() as u64),
+            ),
+            new_text,
+        ),
+    ]
+}
+
+/// Aligns a line starting with a just typed `end` with its block header.
+fn close_block(lines: &[&str], row: usize, col: usize) -> Vec<TextEdit> {
+    let current = lines[row];
+    let typed: String = current.chars().take(col).collect();
+    if typed.trim_left() != "end" {
+        return vec![];
+    }
+
+    let mut depth = 0;
+    let header = lines[..row].iter().rev().find(|line| {
+        match syntax::classify(line) {
+            Line::End(_) => depth += 1,
+            Line::Header(_) if depth == 0 => return true,
+            Line::Header(_) => depth -= 1,
+            Line::Other => {}
+        }
+        false
+    });
+
+    let indent = match header {
+        Some(header) => syntax::indentation(header),
+        None => return vec![],
+    };
+    let current_indent = syntax::indentation(current);
+    if indent == current_indent {
+        return vec![];
+    }
+
+    vec![
+        TextEdit::new(
+            Range::new(
+                Position::new(row as u64, 0),
+                Position::new(row as u64, current_indent.chars().count() as u64),
+            ),
+            indent.to_owned(),
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn options() -> FormattingOptions {
+        FormattingOptions {
+            tab_size: 4,
+            insert_spaces: true,
+            properties: HashMap::new(),
+        }
+    }
+
+    fn apply(text: &str, edits: &[TextEdit]) -> String {
+        let mut lines: Vec<String> = text.split('\n').map(|l| l.to_owned()).collect();
+        // Edits produced here never overlap and are contained in a single line.
+        for edit in edits.iter().rev() {
+            let row = edit.range.start.line as usize;
+            let line: Vec<char> = lines[row].chars().collect();
+            let start = edit.range.start.character as usize;
+            let end = edit.range.end.character as usize;
+            let prefix: String = line[..start].iter().collect();
+            let suffix: String = line[end..].iter().collect();
+            lines[row] = format!("{}{}{}", prefix, edit.new_text, suffix);
+        }
+        lines.join("\n")
+    }
+
+    #[test]
+    fn test_newline_after_header_closes_block() {
+        let text = "rand: Integer is\n";
+        let edits = on_type_formatting(text, Position::new(1, 0), "\n", &options());
+        assert_eq!(apply(text, &edits), "rand: Integer is\n    \nend rand");
+
+        let text = "    rand(x: Integer): Integer is\n    ";
+        let edits = on_type_formatting(text, Position::new(1, 4), "\n", &options());
+        assert_eq!(
+            apply(text, &edits),
+            "    rand(x: Integer): Integer is\n        \n    end rand"
+        );
+    }
+
+    #[test]
+    fn test_newline_in_closed_block_only_indents() {
+        let text = "rand: Integer is\n\nend rand";
+        let edits = on_type_formatting(text, Position::new(1, 0), "\n", &options());
+        assert_eq!(apply(text, &edits), "rand: Integer is\n    \nend rand");
+    }
+
+    #[test]
+    fn test_newline_in_last_nested_block_closes_it() {
+        let text = "outer: Integer is\n    inner: Integer is\n\nend outer";
+        let edits = on_type_formatting(text, Position::new(2, 0), "\n", &options());
+        assert_eq!(
+            apply(text, &edits),
+            "outer: Integer is\n    inner: Integer is\n        \n    end inner\nend outer"
+        );
+
+        let text = "outer: Integer is\n    inner: Integer is\n\n    end other\nend outer";
+        let edits = on_type_formatting(text, Position::new(2, 0), "\n", &options());
+        assert_eq!(
+            apply(text, &edits),
+            "outer: Integer is\n    inner: Integer is\n        \n    end inner\n    end other\nend outer"
+        );
+    }
+
+    #[test]
+    fn test_newline_after_other_lines() {
+        let text = "return 4\n";
+        assert!(on_type_formatting(text, Position::new(1, 0), "\n", &options()).is_empty());
+
+        let text = "this is\n";
+        assert!(on_type_formatting(text, Position::new(1, 0), "\n", &options()).is_empty());
+    }
+
+    #[test]
+    fn test_end_reindents_line() {
+        let text = "rand: Integer is\n    return 4\n    end";
+        let edits = on_type_formatting(text, Position::new(2, 7), "d", &options());
+        assert_eq!(apply(text, &edits), "rand: Integer is\n    return 4\nend");
+
+        let text = "outer: Integer is\n    inner: Integer is\n    end inner\n        end";
+        let edits = on_type_formatting(text, Position::new(3, 11), "d", &options());
+        assert_eq!(
+            apply(text, &edits),
+            "outer: Integer is\n    inner: Integer is\n    end inner\nend"
+        );
+    }
+
+    #[test]
+    fn test_end_ignores_other_words() {
+        let text = "rand: Integer is\n    send";
+        assert!(on_type_formatting(text, Position::new(1, 8), "d", &options()).is_empty());
+
+        let text = "rand: Integer is\nend";
+        assert!(on_type_formatting(text, Position::new(1, 3), "d", &options()).is_empty());
+    }
+}
@@ -8,16 +8,30 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use vfs::Vfs;
+use vfs::{self, FileContents, Vfs};
 use span;
+use url::Url;
+use url_serde;
+use json;
+use jsonrpc;
+use analysis::Analysis;
+use actions::debounce::Debouncer;
+use actions::progress::Background;
+use actions::snapshot::{Files, Snapshot};
+use actions::client_requests::{ClientError, ClientRequests};
+use config::{Config, CONFIG_SECTION};
+use project::{Project, Workspace};
 use lsp_data::Span;
 use lsp_data::*;
 use server::Output;
+use server::panic::lock;
+use logger;
 
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 
 // TODO: Support non-`file` URI schemes in VFS. We're currently ignoring them because
@@ -40,6 +54,11 @@ macro_rules! parse_file_path {
 
 pub mod requests;
 pub mod notifications;
+pub mod format;
+pub mod client_requests;
+pub mod progress;
+pub mod debounce;
+pub mod snapshot;
 
 pub enum ActionContext {
     Init(InitActionContext),
@@ -51,16 +70,87 @@ impl ActionContext {
         ActionContext::Uninit(UninitActionContext::new(vfs))
     }
 
-    pub fn init<O: Output>(&mut self, current_project: PathBuf, init_options: &InitializationOptions, out: O) {
+    pub fn init<O: Output>(
+        &mut self,
+        projects: Vec<Project>,
+        client: ClientFeatures,
+        init_options: &InitializationOptions,
+        out: O,
+    ) {
         let ctx = match *self {
             ActionContext::Uninit(ref uninit) => {
-                InitActionContext::new(uninit.vfs.clone(), current_project)
+                InitActionContext::new(uninit.vfs.clone(), projects, client)
             }
             ActionContext::Init(_) => panic!("ActionContext already initialized"),
         };
+
+        if let Some(ref settings) = init_options.settings {
+            ctx.update_config(settings.akkadia.clone(), &out);
+        }
+
         *self = ActionContext::Init(ctx);
     }
 
+    /// Handles a response from the client to one of our requests.
+    pub fn on_response(&mut self, id: u64, result: Result<json::Value, jsonrpc::Error>) {
+        match *self {
+            ActionContext::Init(ref ctx) => {
+                ctx.client_requests.complete(ctx, id, result.map_err(ClientError::Failed))
+            }
+            ActionContext::Uninit(_) => debug!("on_response: unexpected response {}", id),
+        }
+    }
+
+    /// Stops the background work, on shutdown.
+    pub fn shutdown(&mut self) {
+        if let ActionContext::Init(ref ctx) = *self {
+            ctx.background.cancel();
+        }
+    }
+
+    /// Stops the background work and waits for it, before the server exits.
+    pub fn stop(&mut self) {
+        if let ActionContext::Init(ref ctx) = *self {
+            ctx.background.cancel();
+            ctx.debouncer.stop();
+            ctx.background.join();
+        }
+    }
+
+    /// Gives up on the requests to the client that weren't answered in time.
+    pub fn expire_client_requests(&mut self) {
+        if let ActionContext::Init(ref ctx) = *self {
+            ctx.client_requests.expire(ctx, Instant::now());
+        }
+    }
+
+    /// Returns a context reading from a snapshot of the current state, for
+    /// requests handled concurrently with the messages that follow them.
+    pub fn snapshot(&self) -> ActionContext {
+        match *self {
+            ActionContext::Init(ref ctx) => ActionContext::Init(ctx.snapshot()),
+            ActionContext::Uninit(ref ctx) => {
+                ActionContext::Uninit(UninitActionContext::new(ctx.vfs.clone()))
+            }
+        }
+    }
+
+    /// Projects of the workspace, once initialized.
+    pub fn workspace(&self) -> Option<Arc<Workspace>> {
+        match *self {
+            ActionContext::Init(ref ctx) => Some(ctx.workspace.clone()),
+            ActionContext::Uninit(_) => None,
+        }
+    }
+
+    pub fn is_inited(&self) -> bool {
+        match *self {
+            ActionContext::Init(_) => true,
+            ActionContext::Uninit(_) => false,
+        }
+    }
+
+    /// Handlers only run once the server is initialized, see `dispatch_message`.
     fn inited(&self) -> &InitActionContext {
         match *self {
             ActionContext::Uninit(_) => panic!("ActionContext not initialized"),
@@ -69,9 +159,25 @@ impl ActionContext {
     }
 }
 
+#[derive(Clone)]
 pub struct InitActionContext {
     vfs: Arc<Vfs>,
-    current_project: PathBuf,
+    /// Versions and texts of the files, updated as they change.
+    files: Files,
+    /// The files as seen by this context, if it is a snapshot.
+    snapshot: Option<Snapshot>,
+    /// Analysis of the files outside of every project.
+    analysis: Arc<Analysis>,
+    /// Re-analyzes the files once edits to them settle.
+    debouncer: Debouncer,
+    /// Work shown with a progress, such as checking the projects.
+    background: Background,
+    /// Settings of the files outside of every project, and of the server.
+    config: Arc<Mutex<Config>>,
+    client_requests: ClientRequests,
+    workspace: Arc<Workspace>,
+    /// Features supported by the client, shaping our requests and responses.
+    client: ClientFeatures,
 }
 
 pub struct UninitActionContext {
@@ -89,10 +195,247 @@ impl UninitActionContext {
 
 impl InitActionContext {
     fn new(vfs: Arc<Vfs>,
-           current_project: PathBuf) -> InitActionContext {
+           projects: Vec<Project>,
+           client: ClientFeatures) -> InitActionContext {
         InitActionContext {
             vfs,
-            current_project,
+            files: Files::new(),
+            snapshot: None,
+            analysis: Arc::new(Analysis::new()),
+            debouncer: Debouncer::new(),
+            background: Background::new(),
+            config: Arc::new(Mutex::new(Config::default())),
+            client_requests: ClientRequests::new(),
+            workspace: Arc::new(Workspace::new(projects)),
+            client,
+        }
+    }
+
+    fn snapshot(&self) -> InitActionContext {
+        InitActionContext {
+            snapshot: Some(self.files.snapshot()),
+            config: Arc::new(Mutex::new(lock(&self.config).clone())),
+            ..self.clone()
+        }
+    }
+
+    /// Records that `file_path` changed in the VFS. Its cached analysis is
+    /// outdated, and the requests read from now on see the new text.
+    fn file_changed(&self, file_path: &Path) {
+        // Files no longer cached are read from disk again.
+        let text = match self.vfs.file_is_synced(file_path) {
+            Ok(_) => match self.vfs.load_file(file_path) {
+                Ok(FileContents::Text(text)) => Some(text),
+                _ => None,
+            },
+            Err(_) => None,
+        };
+        self.files.update(file_path, text);
+    }
+
+    /// Version of `file_path` as seen by this context.
+    fn version(&self, file_path: &Path) -> u64 {
+        match self.snapshot {
+            Some(ref snapshot) => snapshot.version(file_path),
+            None => self.files.snapshot().version(file_path),
+        }
+    }
+
+    /// Project owning `file_path`, if any.
+    fn project_for(&self, file_path: &Path) -> Option<Arc<Project>> {
+        self.workspace.project_for(file_path)
+    }
+
+    /// Analysis cache of the project owning `file_path`.
+    fn analysis_for(&self, file_path: &Path) -> Arc<Analysis> {
+        match self.project_for(file_path) {
+            Some(project) => project.analysis.clone(),
+            None => self.analysis.clone(),
+        }
+    }
+
+    /// Settings applying to `file_path`, those of the project owning it.
+    fn config_for(&self, file_path: &Path) -> Config {
+        match self.project_for(file_path) {
+            Some(project) => lock(&project.config).clone(),
+            None => lock(&self.config).clone(),
+        }
+    }
+
+    /// Replaces the settings of the server and of every project.
+    fn update_config<O: Output>(&self, config: Config, out: &O) {
+        let projects = self.workspace
+            .projects()
+            .into_iter()
+            .map(|project| (project, config.clone()))
+            .collect();
+        self.update_configs(config, projects, out);
+    }
+
+    /// Replaces the settings of the server and of the given projects, and
+    /// applies the changed ones.
+    fn update_configs<O: Output>(
+        &self,
+        config: Config,
+        projects: Vec<(Arc<Project>, Config)>,
+        out: &O,
+    ) {
+        trace!("update_configs: {:?}", config);
+
+        let files = self.vfs.get_cached_files();
+        let sources: Vec<(&PathBuf, bool)> = files
+            .keys()
+            .filter(|path| path.extension().map_or(false, |ext| ext == "slang"))
+            .map(|path| (path, self.config_for(path).diagnostics))
+            .collect();
+
+        match config.log_level {
+            Some(level) => logger::set_level(level.to_filter()),
+            None => logger::reset_level(),
+        }
+        logger::set_client_level(config.client_log_level.to_filter());
+
+        *lock(&self.config) = config;
+        for (project, config) in projects {
+            trace!("update_configs: {} {:?}", project.name, config);
+            *lock(&project.config) = config;
+        }
+
+        for (file_path, was_enabled) in sources {
+            let enabled = self.config_for(file_path).diagnostics;
+            if enabled == was_enabled {
+                continue;
+            }
+            if enabled {
+                self.publish_diagnostics(file_path, out);
+            } else if let Ok(uri) = Url::from_file_path(file_path) {
+                clear_diagnostics(uri, out);
+            }
+        }
+    }
+
+    /// Loads the text of `file_path` as seen by this context. Files that
+    /// weren't cached when the snapshot was taken are read from the VFS.
+    fn load_text(&self, file_path: &Path) -> Result<Arc<String>, vfs::Error> {
+        if let Some(text) = self.snapshot.as_ref().and_then(|s| s.text(file_path)) {
+            return Ok(text);
+        }
+        match self.vfs.load_file(file_path)? {
+            FileContents::Text(text) => Ok(Arc::new(text)),
+            FileContents::Binary(_) => Err(vfs::Error::BadFileKind),
+        }
+    }
+
+    /// Whether `file_path` changed since the snapshot was taken.
+    fn is_outdated(&self, file_path: &Path) -> bool {
+        match self.snapshot {
+            Some(ref snapshot) => {
+                snapshot.version(file_path) != self.files.snapshot().version(file_path)
+            }
+            None => false,
+        }
+    }
+
+    /// Asks the client for the current settings with `workspace/configuration`,
+    /// those of the server and those scoped to every project.
+    fn request_config<O: Output>(&self, out: &O) {
+        if !self.client.configuration {
+            debug!("request_config: client can't provide configuration");
+            return;
+        }
+
+        let projects = self.workspace.projects();
+        let item = |scope_uri: Option<Url>| ConfigurationItem {
+            scope_uri: scope_uri.map(url_serde::Serde),
+            section: CONFIG_SECTION.to_owned(),
+        };
+        let mut items = vec![item(None)];
+        items.extend(projects.iter().map(|project| item(Url::from_directory_path(&project.root).ok())));
+
+        let out_ = out.clone();
+        let params = ConfigurationParams { items };
+        self.client_requests.send(out, REQUEST_CONFIGURATION, params, move |ctx, result| {
+            // One result per requested item, in the same order.
+            match result.map(json::from_value::<Vec<Option<Config>>>) {
+                Ok(Ok(items)) => {
+                    let mut items = items.into_iter().map(|item| item.unwrap_or_default());
+                    let config = items.next().unwrap_or_default();
+                    let projects = projects.into_iter().zip(items).collect();
+                    ctx.update_configs(config, projects, &out_);
+                }
+                Ok(Err(e)) => debug!("request_config: invalid settings: {}", e),
+                Err(e) => debug!("request_config: couldn't get configuration: {}", e),
+            }
+        });
+    }
+
+    /// Analyzes every source of `projects` in the background, publishing
+    /// their diagnostics, with the progress shown under `title`.
+    fn check_projects<O: Output>(&self, projects: Vec<Arc<Project>>, title: &str, out: &O) {
+        let out_ = out.clone();
+        progress::spawn(self, out, title, move |ctx, mut progress| {
+            let files: Vec<PathBuf> = projects
+                .iter()
+                .flat_map(|project| project.source_files())
+                .collect();
+            trace!("check_projects: {} files", files.len());
+
+            for (i, file_path) in files.iter().enumerate() {
+                if progress.is_cancelled() {
+                    trace!("check_projects: cancelled");
+                    return;
+                }
+                ctx.publish_diagnostics(file_path, &out_);
+                let name = file_path.file_name().unwrap_or_default().to_string_lossy();
+                progress.report(i + 1, files.len(), format!("{}/{} {}", i + 1, files.len(), name));
+            }
+            progress.end(format!("Checked {} files", files.len()));
+        });
+    }
+
+    /// Publishes diagnostics for `file_path` once it is left unedited for
+    /// the configured delay, only the latest edit is analyzed.
+    fn schedule_diagnostics<O: Output>(&self, file_path: &Path, out: &O) {
+        let delay = Duration::from_millis(self.config_for(file_path).diagnostics_delay);
+        let ctx = self.clone();
+        let out = out.clone();
+        self.debouncer.schedule(file_path, delay, move |run| {
+            let file_path = run.file_path();
+            if let Some(diagnostics) = ctx.diagnostics(file_path) {
+                // Edited again or closed while it was analyzed. Checked along
+                // with the publishing, so that a close clears the diagnostics
+                // after they are published.
+                let published = run.unless_superseded(|| {
+                    notify_diagnostics(file_path, diagnostics, &out)
+                });
+                if !published {
+                    trace!("schedule_diagnostics: dropping outdated {:?}", file_path);
+                }
+            }
+        });
+    }
+
+    /// Publishes diagnostics for `file_path` as currently known by the VFS.
+    fn publish_diagnostics<O: Output>(&self, file_path: &Path, out: &O) {
+        if let Some(diagnostics) = self.diagnostics(file_path) {
+            notify_diagnostics(file_path, diagnostics, out);
+        }
+    }
+
+    /// Diagnostics for `file_path` as currently known by the VFS, if enabled.
+    fn diagnostics(&self, file_path: &Path) -> Option<Vec<Diagnostic>> {
+        if !self.config_for(file_path).diagnostics {
+            return None;
+        }
+
+        let version = self.version(file_path);
+        let load = || self.load_text(file_path);
+        match self.analysis_for(file_path).get(file_path, version, load) {
+            Ok(analysis) => Some(analysis.diagnostics.clone()),
+            Err(e) => {
+                debug!("diagnostics: couldn't analyze {:?}: {}", file_path, e);
+                None
+            }
         }
     }
 
@@ -112,6 +455,29 @@ impl InitActionContext {
     }
 }
 
+fn notify_diagnostics<O: Output>(file_path: &Path, diagnostics: Vec<Diagnostic>, out: &O) {
+    let uri = match Url::from_file_path(file_path) {
+        Ok(uri) => uri,
+        Err(_) => {
+            debug!("publish_diagnostics: invalid file path {:?}", file_path);
+            return;
+        }
+    };
+
+    out.notify(NotificationMessage::new(
+        NOTIFICATION__PublishDiagnostics,
+        Some(PublishDiagnosticsParams::new(uri, diagnostics)),
+    ));
+}
+
+/// Removes all diagnostics previously published for `uri`.
+fn clear_diagnostics<O: Output>(uri: Url, out: &O) {
+    out.notify(NotificationMessage::new(
+        NOTIFICATION__PublishDiagnostics,
+        Some(PublishDiagnosticsParams::new(uri, vec![])),
+    ));
+}
+
 /// Represents a text cursor between characters, pointing at the next character
 /// in the buffer.
 type Column = span::Column<span::ZeroIndexed>;
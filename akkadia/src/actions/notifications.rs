@@ -8,18 +8,26 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use actions::ActionContext;
+use actions::{ActionContext, clear_diagnostics};
+use config::{Config, CONFIG_SECTION};
+use project::Project;
+use url::Url;
 use vfs::Change;
 use serde::Deserialize;
 use serde::de::Error;
 use json;
 use lsp_data::Span;
+use logger::{self, Trace};
 
 use lsp_data::*;
 use server::{Output, Action, NotificationAction, LsState, NoParams};
+use server::panic::lock;
 
 use std::thread;
 
+/// Files the client is asked to watch for us.
+const WATCHED_FILES_GLOB: &'static str = "**/*.slang";
+
 #[derive(Debug, PartialEq)]
 pub struct Initialized;
 
@@ -41,6 +49,37 @@ impl<'a> NotificationAction<'a> for Initialized {
         ctx: &mut ActionContext,
         out: O,
     ) -> Result<(), ()> {
+        const WATCH_ID: &'static str = "akkadia-watch";
+
+        let ctx = ctx.inited();
+        ctx.request_config(&out);
+        ctx.check_projects(ctx.workspace.projects(), "Indexing", &out);
+
+        if !ctx.client.watched_files_registration {
+            debug!("Client can't register watched files, not watching any");
+            return Ok(());
+        }
+
+        let registration = RegistrationParams {
+            registrations: vec![
+                Registration {
+                    id: WATCH_ID.to_owned(),
+                    method: NOTIFICATION__DidChangeWatchedFiles.to_owned(),
+                    register_options: json!({
+                        "watchers": [{ "globPattern": WATCHED_FILES_GLOB }]
+                    }),
+                },
+            ],
+        };
+
+        ctx.client_requests.send(
+            &out,
+            NOTIFICATION__RegisterCapability,
+            registration,
+            |_, result| if let Err(e) = result {
+                debug!("Couldn't register watched files: {}", e);
+            },
+        );
         Ok(())
     }
 }
@@ -62,7 +101,7 @@ impl<'a> NotificationAction<'a> for DidOpen {
         &mut self,
         params: Self::Params,
         ctx: &mut ActionContext,
-        _out: O,
+        out: O,
     ) -> Result<(), ()> {
         trace!("on_open: {:?}", params.text_document.uri);
         let ctx = ctx.inited();
@@ -70,6 +109,8 @@ impl<'a> NotificationAction<'a> for DidOpen {
         let file_path = parse_file_path!(&params.text_document.uri, "on_open")?;
 
         ctx.vfs.set_file(&file_path, &params.text_document.text);
+        ctx.file_changed(&file_path);
+        ctx.schedule_diagnostics(&file_path, &out);
         Ok(())
     }
 }
@@ -123,6 +164,153 @@ impl<'a> NotificationAction<'a> for DidChange {
         ctx.vfs.on_changes(&changes).expect(
             "error committing to VFS",
         );
+        ctx.file_changed(&file_path);
+        ctx.schedule_diagnostics(&file_path, &out);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct DidClose;
+
+impl<'a> Action<'a> for DidClose {
+    type Params = DidCloseTextDocumentParams;
+    const METHOD: &'static str = "textDocument/didClose";
+
+    fn new(_: &'a mut LsState) -> Self {
+        DidClose
+    }
+}
+
+impl<'a> NotificationAction<'a> for DidClose {
+    fn handle<O: Output>(
+        &mut self,
+        params: Self::Params,
+        ctx: &mut ActionContext,
+        out: O,
+    ) -> Result<(), ()> {
+        trace!("on_close: {:?}", params.text_document.uri);
+        let ctx = ctx.inited();
+
+        let file_path = parse_file_path!(&params.text_document.uri, "on_close")?;
+
+        // Closing the buffer discards any unsaved edits, the file contents on
+        // disk become the source of truth again.
+        ctx.vfs.flush_file(&file_path).map_err(|e| {
+            debug!("on_close: couldn't flush {:?}: {}", file_path, e);
+        })?;
+        ctx.file_changed(&file_path);
+        ctx.debouncer.cancel(&file_path);
+
+        clear_diagnostics(params.text_document.uri, &out);
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct DidChangeConfiguration;
+
+impl<'a> Action<'a> for DidChangeConfiguration {
+    type Params = DidChangeConfigurationParams;
+    const METHOD: &'static str = "workspace/didChangeConfiguration";
+
+    fn new(_: &'a mut LsState) -> Self {
+        DidChangeConfiguration
+    }
+}
+
+impl<'a> NotificationAction<'a> for DidChangeConfiguration {
+    fn handle<O: Output>(
+        &mut self,
+        params: DidChangeConfigurationParams,
+        ctx: &mut ActionContext,
+        out: O,
+    ) -> Result<(), ()> {
+        trace!("config change: {:?}", params.settings);
+        let ctx = ctx.inited();
+
+        match params.settings.get(CONFIG_SECTION) {
+            Some(settings) => {
+                let config = json::from_value::<Config>(settings.to_owned()).map_err(|e| {
+                    debug!("Received unactionable config: {:?} (error: {})", settings, e);
+                })?;
+                ctx.update_config(config, &out);
+            }
+            // Clients preferring the pull model don't send the settings.
+            None => ctx.request_config(&out),
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct DidChangeWorkspaceFolders;
+
+impl<'a> Action<'a> for DidChangeWorkspaceFolders {
+    type Params = DidChangeWorkspaceFoldersParams;
+    const METHOD: &'static str = "workspace/didChangeWorkspaceFolders";
+
+    fn new(_: &'a mut LsState) -> Self {
+        DidChangeWorkspaceFolders
+    }
+}
+
+impl<'a> NotificationAction<'a> for DidChangeWorkspaceFolders {
+    fn handle<O: Output>(
+        &mut self,
+        params: DidChangeWorkspaceFoldersParams,
+        ctx: &mut ActionContext,
+        out: O,
+    ) -> Result<(), ()> {
+        trace!("workspace folders change: {:?}", params.event);
+        let ctx = ctx.inited();
+
+        for folder in &params.event.removed {
+            let root = match parse_file_path!(&folder.uri, "on_workspace_folders_change") {
+                Ok(root) => root,
+                Err(()) => continue,
+            };
+            if ctx.workspace.remove(&root).is_none() {
+                debug!("Removed unknown workspace folder {:?}", folder.uri);
+                continue;
+            }
+
+            // Forget the files of the project unless they're still open.
+            for file_path in ctx.vfs.get_cached_files().keys() {
+                if !file_path.starts_with(&root) || ctx.workspace.project_for(file_path).is_some() {
+                    continue;
+                }
+                if let Ok(false) = ctx.vfs.file_is_synced(file_path) {
+                    continue;
+                }
+                if let Err(e) = ctx.vfs.flush_file(file_path) {
+                    debug!("Couldn't flush {:?}: {}", file_path, e);
+                }
+                ctx.file_changed(file_path);
+                if let Ok(uri) = Url::from_file_path(file_path) {
+                    clear_diagnostics(uri, &out);
+                }
+            }
+        }
+
+        let mut added = vec![];
+        for folder in &params.event.added {
+            match Project::from_folder(folder) {
+                Ok(project) => {
+                    // Until the client tells the settings scoped to the project.
+                    *lock(&project.config) = lock(&ctx.config).clone();
+                    added.push(ctx.workspace.add(project));
+                }
+                Err(e) => debug!("Ignoring workspace folder {:?}: {}", folder.uri, e),
+            }
+        }
+        if !added.is_empty() {
+            ctx.request_config(&out);
+            ctx.check_projects(added, "Checking", &out);
+        }
+
         Ok(())
     }
 }
@@ -142,11 +330,37 @@ impl<'a> Action<'a> for Cancel {
 impl<'a> NotificationAction<'a> for Cancel {
     fn handle<O: Output>(
         &mut self,
-        _params: CancelParams,
+        params: CancelParams,
+        _ctx: &mut ActionContext,
+        _out: O,
+    ) -> Result<(), ()> {
+        // The request has been cancelled already when this notification was
+        // read, see `PendingRequests::track`.
+        trace!("Cancelled request {:?}", params.id);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct SetTrace;
+
+impl<'a> Action<'a> for SetTrace {
+    type Params = SetTraceParams;
+    const METHOD: &'static str = "$/setTrace";
+
+    fn new(_: &'a mut LsState) -> Self {
+        SetTrace
+    }
+}
+
+impl<'a> NotificationAction<'a> for SetTrace {
+    fn handle<O: Output>(
+        &mut self,
+        params: SetTraceParams,
         _ctx: &mut ActionContext,
         _out: O,
     ) -> Result<(), ()> {
-        // Nothing to do.
+        logger::set_trace(Trace::new(&params.value));
         Ok(())
     }
 }
@@ -195,10 +409,45 @@ impl<'a> Action<'a> for DidChangeWatchedFiles {
 impl<'a> NotificationAction<'a> for DidChangeWatchedFiles {
     fn handle<O: Output>(
         &mut self,
-        _params: DidChangeWatchedFilesParams,
+        params: DidChangeWatchedFilesParams,
         ctx: &mut ActionContext,
         out: O,
     ) -> Result<(), ()> {
+        trace!("on_watched_change: {:?}", params);
+        let ctx = ctx.inited();
+
+        for event in params.changes {
+            let file_path = match parse_file_path!(&event.uri, "on_watched_change") {
+                Ok(file_path) => file_path,
+                Err(()) => continue,
+            };
+
+            // Open buffers are managed by the client, don't replace their
+            // unsaved contents with whatever is on disk now.
+            if let Ok(false) = ctx.vfs.file_is_synced(&file_path) {
+                trace!("on_watched_change: keeping dirty buffer {:?}", file_path);
+                continue;
+            }
+
+            // Forget the cached contents, they're reloaded from disk when needed.
+            if let Err(e) = ctx.vfs.flush_file(&file_path) {
+                debug!("on_watched_change: couldn't flush {:?}: {}", file_path, e);
+                continue;
+            }
+            ctx.file_changed(&file_path);
+
+            if file_path.extension().map_or(true, |ext| ext != "slang") {
+                continue;
+            }
+
+            match event.typ {
+                FileChangeType::Deleted => clear_diagnostics(event.uri, &out),
+                FileChangeType::Created | FileChangeType::Changed => {
+                    ctx.publish_diagnostics(&file_path, &out);
+                }
+            }
+        }
+
         Ok(())
     }
 }
@@ -0,0 +1,220 @@
+// Copyright 2017 Mike Lubinets <lubinetsm@yandex.ru>.
+// See the COPYRIGHT file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Long running work done in the background, with its progress shown by the
+//! client through `window/workDoneProgress/create` and `$/progress`.
+
+use actions::InitActionContext;
+use lsp_data::{NotificationMessage, ProgressParams, WorkDoneProgress,
+               WorkDoneProgressCreateParams, NOTIFICATION_PROGRESS,
+               REQUEST_WORK_DONE_PROGRESS_CREATE};
+use server::Output;
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::thread::{self, JoinHandle};
+
+static NEXT_TOKEN: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Threads of the work started with `spawn`, stopped on shutdown.
+#[derive(Clone, Default)]
+pub struct Background {
+    cancelled: Arc<AtomicBool>,
+    threads: Arc<Mutex<Vec<Thread>>>,
+}
+
+struct Thread {
+    /// Set once the work is done, even if it panicked.
+    finished: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+/// Marks a thread as finished when dropped.
+struct Finished(Arc<AtomicBool>);
+
+impl Drop for Finished {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Background {
+    pub fn new() -> Background {
+        Background::default()
+    }
+
+    /// Asks the running work to stop, no new work is started afterwards.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Waits for the work started so far to finish.
+    pub fn join(&self) {
+        let threads: Vec<_> = self.threads.lock().unwrap().drain(..).collect();
+        for thread in threads {
+            if thread.handle.join().is_err() {
+                debug!("A background thread panicked");
+            }
+        }
+    }
+
+    fn add(&self, thread: Thread) {
+        let mut threads = self.threads.lock().unwrap();
+        // Forget the finished threads, a long session starts many.
+        threads.retain(|thread| !thread.finished.load(Ordering::SeqCst));
+        threads.push(thread);
+    }
+}
+
+/// Runs `work` on a new thread. If the client can show progress, it is asked
+/// to create a progress for `title` first, `work` starts once it answers.
+pub fn spawn<O, F>(ctx: &InitActionContext, out: &O, title: &str, work: F)
+where
+    O: Output,
+    F: FnOnce(&InitActionContext, Progress<O>) + Send + 'static,
+{
+    let title = title.to_owned();
+    if !ctx.client.work_done_progress {
+        run(ctx, None, out.clone(), title, work);
+        return;
+    }
+
+    let token = format!("akkadia/{}", NEXT_TOKEN.fetch_add(1, Ordering::SeqCst));
+    let params = WorkDoneProgressCreateParams { token: token.clone() };
+    let out_ = out.clone();
+    ctx.client_requests.send(out, REQUEST_WORK_DONE_PROGRESS_CREATE, params, move |ctx, result| {
+        // Do the work anyway, just without showing it.
+        let token = match result {
+            Ok(_) => Some(token),
+            Err(e) => {
+                debug!("Couldn't create progress for `{}`: {}", title, e);
+                None
+            }
+        };
+        run(ctx, token, out_, title, work);
+    });
+}
+
+fn run<O, F>(ctx: &InitActionContext, token: Option<String>, out: O, title: String, work: F)
+where
+    O: Output,
+    F: FnOnce(&InitActionContext, Progress<O>) + Send + 'static,
+{
+    // The client may answer `window/workDoneProgress/create` after shutdown.
+    if ctx.background.is_cancelled() {
+        debug!("Not starting `{}` after shutdown", title);
+        return;
+    }
+
+    let background = ctx.background.clone();
+    let progress = Progress::begin(token, out, title, background.cancelled.clone());
+    let ctx = ctx.clone();
+    let finished = Arc::new(AtomicBool::new(false));
+    let finished_ = Finished(finished.clone());
+    let result = thread::Builder::new()
+        .name("progress".to_owned())
+        .spawn(move || {
+            let _finished = finished_;
+            work(&ctx, progress)
+        });
+    match result {
+        Ok(handle) => background.add(Thread { finished, handle }),
+        Err(e) => debug!("Couldn't spawn a thread for the work: {}", e),
+    }
+}
+
+/// Progress of a work shown by the client, ended when dropped.
+pub struct Progress<O: Output> {
+    /// `None` if the client doesn't show the progress.
+    token: Option<String>,
+    out: O,
+    percentage: u32,
+    ended: bool,
+    /// Set on shutdown, nothing is sent to the client afterwards.
+    cancelled: Arc<AtomicBool>,
+}
+
+impl<O: Output> Progress<O> {
+    fn begin(
+        token: Option<String>,
+        out: O,
+        title: String,
+        cancelled: Arc<AtomicBool>,
+    ) -> Progress<O> {
+        let progress = Progress {
+            token,
+            out,
+            percentage: 0,
+            ended: false,
+            cancelled,
+        };
+        progress.notify(WorkDoneProgress::Begin {
+            title,
+            message: None,
+            percentage: Some(0),
+        });
+        progress
+    }
+
+    /// Reports that `done` out of `total` steps are done. Only changes of
+    /// the percentage are sent, not to flood the client.
+    pub fn report(&mut self, done: usize, total: usize, message: String) {
+        let percentage = if total == 0 {
+            100
+        } else {
+            (done * 100 / total) as u32
+        };
+        if percentage == self.percentage {
+            return;
+        }
+
+        self.percentage = percentage;
+        self.notify(WorkDoneProgress::Report {
+            message: Some(message),
+            percentage: Some(percentage),
+        });
+    }
+
+    /// Whether the server is shutting down, the work should stop then.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn end(mut self, message: String) {
+        self.ended = true;
+        self.notify(WorkDoneProgress::End { message: Some(message) });
+    }
+
+    fn notify(&self, value: WorkDoneProgress) {
+        let token = match self.token {
+            Some(ref token) => token.clone(),
+            None => return,
+        };
+        if self.is_cancelled() {
+            return;
+        }
+        self.out.notify(NotificationMessage::new(
+            NOTIFICATION_PROGRESS,
+            Some(ProgressParams { token, value }),
+        ));
+    }
+}
+
+impl<O: Output> Drop for Progress<O> {
+    fn drop(&mut self) {
+        if !self.ended {
+            self.notify(WorkDoneProgress::End { message: None });
+        }
+    }
+}
@@ -8,7 +8,10 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use actions::ActionContext;
+use actions::{ActionContext, InitActionContext};
+use actions::format;
+use config::FormatterStyle;
+use syntax;
 use url::Url;
 use vfs::FileContents;
 use json;
@@ -16,11 +19,14 @@ use span;
 
 use lsp_data;
 use lsp_data::*;
-use server::{Output, Ack, Action, RequestAction, LsState};
+use server::{Output, Ack, Action, RequestAction, LsState, CancelToken, ResponseError};
+use server::{NoParams, PendingRequests, Stats};
+use jsonrpc::Id;
 use jsonrpc::types::ErrorCode;
 
 use std::collections::HashMap;
-use std::panic;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
@@ -39,18 +45,28 @@ impl<'a> RequestAction<'a> for Completion {
     type Response = Vec<CompletionItem>;
     fn handle<O: Output>(
         &mut self,
-        _id: usize,
+        _id: Id,
         params: Self::Params,
+        token: &CancelToken,
         ctx: &mut ActionContext,
         _out: O,
-    ) -> Result<Self::Response, ()> {
+    ) -> Result<Self::Response, ResponseError> {
         let ctx = ctx.inited();
         let vfs = ctx.vfs.clone();
-        let file_path = parse_file_path!(&params.text_document.uri, "complete")?;
+        let file_path = document_path(&params.text_document.uri, "complete")?;
 
-        let result = vec![ 
-            CompletionItem::new_simple("completion".to_owned(), "test completion".to_owned())
-        ];
+        let result = vec![CompletionItem {
+            documentation: Some(Documentation::new(
+                ctx.client.completion_markdown,
+                "*test completion*",
+                "test completion",
+            )),
+            ..CompletionItem::new_simple("completion".to_owned(), "test completion".to_owned())
+        }];
+
+        if token.is_cancelled() {
+            return Err(ResponseError::Cancelled);
+        }
 
         Ok(result)
     }
@@ -71,14 +87,161 @@ impl<'a> RequestAction<'a> for ResolveCompletion {
     type Response = Vec<CompletionItem>;
     fn handle<O: Output>(
         &mut self,
-        _id: usize,
+        _id: Id,
         params: Self::Params,
+        _token: &CancelToken,
         _ctx: &mut ActionContext,
         _out: O,
-    ) -> Result<Self::Response, ()> {
+    ) -> Result<Self::Response, ResponseError> {
         // currently, we safely ignore this as a pass-through since we fully handle
         // textDocument/completion.  In the future, we may want to use this method as a
         // way to more lazily fill out completion information
         Ok(vec![params])
     }
 }
+
+pub struct OnTypeFormatting;
+
+impl<'a> Action<'a> for OnTypeFormatting {
+    type Params = DocumentOnTypeFormattingParams;
+    const METHOD: &'static str = "textDocument/onTypeFormatting";
+
+    fn new(_: &'a mut LsState) -> Self {
+        OnTypeFormatting
+    }
+}
+
+impl<'a> RequestAction<'a> for OnTypeFormatting {
+    type Response = Vec<TextEdit>;
+    fn handle<O: Output>(
+        &mut self,
+        _id: Id,
+        params: Self::Params,
+        _token: &CancelToken,
+        ctx: &mut ActionContext,
+        _out: O,
+    ) -> Result<Self::Response, ResponseError> {
+        let ctx = ctx.inited();
+        let file_path = document_path(&params.text_document.uri, "on_type_formatting")?;
+        let text = load_text(ctx, &file_path, "on_type_formatting")?;
+
+        let mut options = params.options;
+        match ctx.config_for(&file_path).formatter {
+            FormatterStyle::Editor => {}
+            FormatterStyle::Spaces(width) => {
+                options.insert_spaces = true;
+                options.tab_size = width;
+            }
+            FormatterStyle::Tabs => options.insert_spaces = false,
+        }
+
+        let edits = format::on_type_formatting(&text, params.position, &params.ch, &options);
+
+        // Edits to another version of the document would garble it.
+        if ctx.is_outdated(&file_path) {
+            return Err(ResponseError::ContentModified);
+        }
+        Ok(edits)
+    }
+}
+
+/// Pretty-prints the syntax tree of a document, to debug the parser.
+pub struct SyntaxTree;
+
+impl<'a> Action<'a> for SyntaxTree {
+    type Params = SyntaxTreeParams;
+    const METHOD: &'static str = "akkadia/syntaxTree";
+
+    fn new(_: &'a mut LsState) -> Self {
+        SyntaxTree
+    }
+}
+
+impl<'a> RequestAction<'a> for SyntaxTree {
+    type Response = String;
+    fn handle<O: Output>(
+        &mut self,
+        _id: Id,
+        params: Self::Params,
+        _token: &CancelToken,
+        ctx: &mut ActionContext,
+        _out: O,
+    ) -> Result<Self::Response, ResponseError> {
+        let ctx = ctx.inited();
+        let file_path = document_path(&params.text_document.uri, "syntax_tree")?;
+
+        let text = load_text(ctx, &file_path, "syntax_tree")?;
+        let tree = syntax::syntax_tree(&text);
+        let node = match params.range {
+            Some(range) => {
+                let point = |pos: Position| (pos.line as usize, pos.character as usize);
+                tree.covering(point(range.start), point(range.end))
+            }
+            None => &tree,
+        };
+        Ok(node.dump())
+    }
+}
+
+/// Reports how busy the server is and how much it holds in memory.
+pub struct ServerStatusRequest {
+    stats: Stats,
+    pending: PendingRequests,
+}
+
+impl<'a> Action<'a> for ServerStatusRequest {
+    type Params = NoParams;
+    const METHOD: &'static str = "akkadia/serverStatus";
+
+    fn new(state: &'a mut LsState) -> Self {
+        ServerStatusRequest {
+            stats: state.stats.clone(),
+            pending: state.pending.clone(),
+        }
+    }
+}
+
+impl<'a> RequestAction<'a> for ServerStatusRequest {
+    type Response = ServerStatus;
+    fn handle<O: Output>(
+        &mut self,
+        _id: Id,
+        _params: Self::Params,
+        _token: &CancelToken,
+        ctx: &mut ActionContext,
+        _out: O,
+    ) -> Result<Self::Response, ResponseError> {
+        let ctx = ctx.inited();
+        let files = ctx.vfs.get_cached_files();
+
+        Ok(ServerStatus {
+            requests: self.stats.methods(),
+            vfs: VfsStatus {
+                files: files.len(),
+                bytes: files.values().map(|text| text.len()).sum(),
+            },
+            cached_analyses: ctx.workspace
+                .projects()
+                .iter()
+                .fold(ctx.analysis.len(), |len, project| len + project.analysis.len()),
+            // Not counting this request.
+            queue_depth: self.pending.len().saturating_sub(1),
+        })
+    }
+}
+
+fn document_path(uri: &Url, log_name: &str) -> Result<PathBuf, ResponseError> {
+    parse_file_path!(uri, log_name)
+        .map_err(|_| ResponseError::InvalidParams(format!("not a file URI: {}", uri)))
+}
+
+fn load_text(
+    ctx: &InitActionContext,
+    file_path: &Path,
+    log_name: &str,
+) -> Result<Arc<String>, ResponseError> {
+    ctx.load_text(file_path).map_err(|e| {
+        debug!("{}: couldn't load {:?}: {}", log_name, file_path, e);
+        ResponseError::InvalidParams(format!("couldn't load {}: {}", file_path.display(), e))
+    })
+}
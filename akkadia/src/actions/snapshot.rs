@@ -0,0 +1,102 @@
+// Copyright 2017 Mike Lubinets <lubinetsm@yandex.ru>.
+// See the COPYRIGHT file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Versions and texts of the files known to the VFS, shared with the
+//! requests handled concurrently so that they see the files as they were
+//! when the request was read.
+
+use server::panic::lock;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone)]
+struct File {
+    /// Bumped on every change of the file.
+    version: u64,
+    /// `None` if the file isn't cached by the VFS, it is read from disk then.
+    text: Option<Arc<String>>,
+}
+
+/// The files at some point in time. Cloning it is cheap, the texts are shared.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    files: Arc<HashMap<PathBuf, File>>,
+}
+
+impl Snapshot {
+    /// Version of `path`, 0 if it never changed.
+    pub fn version(&self, path: &Path) -> u64 {
+        self.files.get(path).map_or(0, |file| file.version)
+    }
+
+    /// Text of `path` if it was cached by the VFS.
+    pub fn text(&self, path: &Path) -> Option<Arc<String>> {
+        self.files.get(path).and_then(|file| file.text.clone())
+    }
+}
+
+/// The latest snapshot, updated as the files change. The map is only copied
+/// on a change while an older snapshot is still in use.
+#[derive(Debug, Clone, Default)]
+pub struct Files {
+    current: Arc<Mutex<Snapshot>>,
+}
+
+impl Files {
+    pub fn new() -> Files {
+        Files::default()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        lock(&self.current).clone()
+    }
+
+    /// Records a new version of `path`, with its text if cached by the VFS.
+    pub fn update(&self, path: &Path, text: Option<String>) {
+        let mut current = lock(&self.current);
+        let files = Arc::make_mut(&mut current.files);
+        let version = files.get(path).map_or(0, |file| file.version) + 1;
+        files.insert(
+            path.to_owned(),
+            File {
+                version,
+                text: text.map(Arc::new),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let files = Files::new();
+        let path = Path::new("main.slang");
+        assert_eq!(files.snapshot().version(path), 0);
+
+        files.update(path, Some("before".to_owned()));
+        let before = files.snapshot();
+        files.update(path, Some("after".to_owned()));
+        files.update(Path::new("lib.slang"), None);
+
+        assert_eq!(before.version(path), 1);
+        assert_eq!(before.text(path).unwrap().as_str(), "before");
+        assert_eq!(before.version(Path::new("lib.slang")), 0);
+
+        let after = files.snapshot();
+        assert_eq!(after.version(path), 2);
+        assert_eq!(after.text(path).unwrap().as_str(), "after");
+        assert_eq!(after.text(Path::new("lib.slang")), None);
+    }
+}
@@ -0,0 +1,167 @@
+// Copyright 2017 Mike Lubinets <lubinetsm@yandex.ru>.
+// See the COPYRIGHT file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Per-file analysis results, cached by version of the file.
+
+use vfs;
+use lsp_data::*;
+use syntax::{self, Line};
+use server::panic::lock;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub struct Analysis {
+    files: Mutex<Files>,
+}
+
+/// Latest analysis of every file, with the version of the file analyzed.
+type Files = HashMap<PathBuf, (u64, Arc<FileAnalysis>)>;
+
+impl Analysis {
+    pub fn new() -> Analysis {
+        Analysis { files: Mutex::new(Files::new()) }
+    }
+
+    /// Returns the analysis of `version` of `path`, analyzing the text given
+    /// by `load` if there is no cached result for that version.
+    pub fn get<F>(&self, path: &Path, version: u64, load: F) -> Result<Arc<FileAnalysis>, vfs::Error>
+    where
+        F: FnOnce() -> Result<Arc<String>, vfs::Error>,
+    {
+        if let Some(&(cached, ref analysis)) = lock(&self.files).get(path) {
+            if cached == version {
+                return Ok(analysis.clone());
+            }
+        }
+
+        // Don't hold the lock while analyzing.
+        let analysis = Arc::new(FileAnalysis::new(&load()?));
+
+        // Keep the analysis of the latest version, snapshots may be older.
+        let mut files = lock(&self.files);
+        let outdated = files.get(path).map_or(false, |&(cached, _)| cached > version);
+        if !outdated {
+            files.insert(path.to_owned(), (version, analysis.clone()));
+        }
+        Ok(analysis)
+    }
+
+    /// Number of files with a cached analysis.
+    pub fn len(&self) -> usize {
+        lock(&self.files).len()
+    }
+}
+
+#[derive(Debug)]
+pub struct FileAnalysis {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl FileAnalysis {
+    pub fn new(text: &str) -> FileAnalysis {
+        FileAnalysis { diagnostics: check_blocks(text) }
+    }
+}
+
+/// Reports unbalanced and mismatched `name: Type is` ... `end name` blocks.
+fn check_blocks(text: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    let mut open: Vec<(&str, u64, &str)> = vec![];
+
+    for (row, line) in text.lines().enumerate() {
+        let row = row as u64;
+        match syntax::classify(line) {
+            Line::Header(name) => open.push((name, row, line)),
+            Line::End(name) => match (open.pop(), name) {
+                (None, _) => {
+                    diagnostics.push(error(row, line, "unexpected `end`".to_owned()));
+                }
+                (Some((expected, _, _)), Some(found)) if expected != found => {
+                    diagnostics.push(error(
+                        row,
+                        line,
+                        format!("expected `end {}`, found `end {}`", expected, found),
+                    ));
+                }
+                _ => {}
+            },
+            Line::Other => {}
+        }
+    }
+
+    for (name, row, line) in open {
+        diagnostics.push(error(row, line, format!("block `{}` is never closed", name)));
+    }
+
+    diagnostics
+}
+
+fn error(row: u64, line: &str, message: String) -> Diagnostic {
+    let start = syntax::indentation(line).chars().count() as u64;
+    let end = line.trim_right().chars().count() as u64;
+
+    Diagnostic::new(
+        Range::new(Position::new(row, start), Position::new(row, end)),
+        Some(DiagnosticSeverity::Error),
+        None,
+        Some("akkadia".to_owned()),
+        message,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn messages(text: &str) -> Vec<(u64, String)> {
+        check_blocks(text)
+            .into_iter()
+            .map(|d| (d.range.start.line, d.message))
+            .collect()
+    }
+
+    #[test]
+    fn test_cached_by_version() {
+        let analysis = Analysis::new();
+        let path = Path::new("main.slang");
+        let text = |text: &str| Ok(Arc::new(text.to_owned()));
+
+        let unclosed = analysis.get(path, 1, || text("rand: Integer is\n")).unwrap();
+        assert_eq!(unclosed.diagnostics.len(), 1);
+        let cached = analysis.get(path, 1, || panic!("analyzed again")).unwrap();
+        assert_eq!(cached.diagnostics.len(), 1);
+
+        let closed = analysis.get(path, 2, || text("rand: Integer is\nend\n")).unwrap();
+        assert!(closed.diagnostics.is_empty());
+        // An older snapshot doesn't replace the latest result.
+        analysis.get(path, 1, || text("rand: Integer is\n")).unwrap();
+        assert!(analysis.get(path, 2, || panic!("analyzed again")).unwrap().diagnostics.is_empty());
+    }
+
+    #[test]
+    fn test_check_blocks() {
+        assert!(messages("rand: Integer is\n return 4\nend rand\n").is_empty());
+        assert!(messages("rand: Integer is\n return 4\nend\n").is_empty());
+
+        assert_eq!(
+            messages("rand: Integer is\n return 4\n"),
+            vec![(0, "block `rand` is never closed".to_owned())]
+        );
+        assert_eq!(
+            messages("rand: Integer is\nend other\nend\n"),
+            vec![
+                (1, "expected `end rand`, found `end other`".to_owned()),
+                (2, "unexpected `end`".to_owned()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,163 @@
+// Copyright 2017 Mike Lubinets <lubinetsm@yandex.ru>.
+// See the COPYRIGHT file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Command line of the `akkadia` binary.
+
+use log::LogLevelFilter;
+use server::Transport;
+
+use std::path::PathBuf;
+
+pub const USAGE: &'static str = "\
+Usage: akkadia [options]
+
+Options:
+    --stdio                 Talk to the client over stdin and stdout (default)
+    --listen <address>      Wait for the client on `[tcp:]host:port` or `unix:path`
+    --log-file <path>       Append logs to the file, besides stderr [default: .akkadia.log]
+    --log-level <level>     off, error, warn, info, debug or trace [default: trace]
+    --record <path>         Record the session to a trace file, for bug reports
+    --replay <path>         Replay a recorded session and compare the answers
+    -V, --version           Print the version and exit
+    -h, --help              Print this message and exit";
+
+const DEFAULT_LOG_FILE: &'static str = ".akkadia.log";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run(Options),
+    Version,
+    Help,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub transport: Transport,
+    pub log_file: PathBuf,
+    pub log_level: LogLevelFilter,
+    /// Trace file to record the session to.
+    pub record: Option<PathBuf>,
+    /// Trace file to replay instead of serving a client.
+    pub replay: Option<PathBuf>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            transport: Transport::Stdio,
+            log_file: PathBuf::from(DEFAULT_LOG_FILE),
+            log_level: LogLevelFilter::Trace,
+            record: None,
+            replay: None,
+        }
+    }
+}
+
+/// Parses the arguments following the program name. Values are given
+/// either as the next argument or after `=`, as in `--log-level=info`.
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut options = Options::default();
+    let mut transport = None;
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        let (flag, inline_value) = match arg.find('=') {
+            Some(eq) if arg.starts_with("--") => (arg[..eq].to_owned(), Some(arg[eq + 1..].to_owned())),
+            _ => (arg.clone(), None),
+        };
+        let mut value = || {
+            inline_value
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("missing value for `{}`", flag))
+        };
+
+        match &flag[..] {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            "--stdio" => set_transport(&mut transport, Transport::Stdio)?,
+            "--listen" => set_transport(&mut transport, value()?.parse()?)?,
+            "--log-file" => options.log_file = PathBuf::from(value()?),
+            "--record" => options.record = Some(PathBuf::from(value()?)),
+            "--replay" => options.replay = Some(PathBuf::from(value()?)),
+            "--log-level" => {
+                let level = value()?;
+                options.log_level = level
+                    .parse()
+                    .map_err(|_| format!("invalid log level `{}`", level))?;
+            }
+            _ => return Err(format!("unexpected argument `{}`", arg)),
+        }
+    }
+
+    if options.replay.is_some() && (transport.is_some() || options.record.is_some()) {
+        return Err("`--replay` can't be combined with `--stdio`, `--listen` or `--record`".to_owned());
+    }
+    if let Some(transport) = transport {
+        options.transport = transport;
+    }
+    Ok(Command::Run(options))
+}
+
+fn set_transport(transport: &mut Option<Transport>, value: Transport) -> Result<(), String> {
+    if transport.is_some() {
+        return Err("only one of `--stdio` and `--listen` can be given".to_owned());
+    }
+    *transport = Some(value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse_args(args: &str) -> Result<Command, String> {
+        parse(args.split_whitespace().map(|arg| arg.to_owned()))
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_args(""), Ok(Command::Run(Options::default())));
+        assert_eq!(parse_args("--stdio"), Ok(Command::Run(Options::default())));
+        assert_eq!(parse_args("--log-level info -V"), Ok(Command::Version));
+        assert_eq!(parse_args("--help"), Ok(Command::Help));
+
+        assert_eq!(
+            parse_args("--listen unix:/tmp/akkadia.sock --log-file /tmp/akkadia.log --log-level=WARN"),
+            Ok(Command::Run(Options {
+                transport: Transport::Unix(PathBuf::from("/tmp/akkadia.sock")),
+                log_file: PathBuf::from("/tmp/akkadia.log"),
+                log_level: LogLevelFilter::Warn,
+                ..Options::default()
+            }))
+        );
+        assert_eq!(
+            parse_args("--record session.jsonl"),
+            Ok(Command::Run(Options {
+                record: Some(PathBuf::from("session.jsonl")),
+                ..Options::default()
+            }))
+        );
+        assert_eq!(
+            parse_args("--listen=127.0.0.1:9257"),
+            Ok(Command::Run(Options {
+                transport: Transport::Tcp("127.0.0.1:9257".parse().unwrap()),
+                ..Options::default()
+            }))
+        );
+
+        assert!(parse_args("--log-level").is_err());
+        assert!(parse_args("--log-level loud").is_err());
+        assert!(parse_args("--listen nowhere").is_err());
+        assert!(parse_args("--stdio --listen 127.0.0.1:9257").is_err());
+        assert!(parse_args("--verbose").is_err());
+        assert!(parse_args("--replay session.jsonl --stdio").is_err());
+    }
+}
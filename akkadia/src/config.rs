@@ -0,0 +1,122 @@
+// Copyright 2017 Mike Lubinets <lubinetsm@yandex.ru>.
+// See the COPYRIGHT file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! User settings of the server, under the `akkadia` section of the client
+//! configuration. Settings can be changed at any time while the server runs.
+
+use log::LogLevelFilter;
+
+/// Name of the configuration section holding the settings.
+pub const CONFIG_SECTION: &'static str = "akkadia";
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    /// Publish diagnostics for Slang sources.
+    pub diagnostics: bool,
+    /// Milliseconds without edits to a file before it is analyzed again.
+    #[serde(rename = "diagnosticsDelay")]
+    pub diagnostics_delay: u64,
+    /// Indentation used when formatting.
+    pub formatter: FormatterStyle,
+    /// Verbosity of server logs, the level given on the command line if missing.
+    #[serde(rename = "logLevel")]
+    pub log_level: Option<LogLevel>,
+    /// Verbosity of the logs shown by the client, see `window/logMessage`.
+    #[serde(rename = "clientLogLevel")]
+    pub client_log_level: LogLevel,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            diagnostics: true,
+            diagnostics_delay: 200,
+            formatter: FormatterStyle::Editor,
+            log_level: None,
+            client_log_level: LogLevel::Info,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum FormatterStyle {
+    /// Follow the formatting options sent along with the request.
+    #[serde(rename = "editor")]
+    Editor,
+    /// Indent with the given amount of spaces.
+    #[serde(rename = "spaces")]
+    Spaces(u64),
+    /// Indent with tabs.
+    #[serde(rename = "tabs")]
+    Tabs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum LogLevel {
+    #[serde(rename = "off")]
+    Off,
+    #[serde(rename = "error")]
+    Error,
+    #[serde(rename = "warn")]
+    Warn,
+    #[serde(rename = "info")]
+    Info,
+    #[serde(rename = "debug")]
+    Debug,
+    #[serde(rename = "trace")]
+    Trace,
+}
+
+impl LogLevel {
+    pub fn to_filter(self) -> LogLevelFilter {
+        match self {
+            LogLevel::Off => LogLevelFilter::Off,
+            LogLevel::Error => LogLevelFilter::Error,
+            LogLevel::Warn => LogLevelFilter::Warn,
+            LogLevel::Info => LogLevelFilter::Info,
+            LogLevel::Debug => LogLevelFilter::Debug,
+            LogLevel::Trace => LogLevelFilter::Trace,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use json;
+
+    #[test]
+    fn test_deserialize_config() {
+        let config: Config = json::from_value(json!({})).unwrap();
+        assert_eq!(config, Config::default());
+
+        let config: Config = json::from_value(json!({
+            "diagnostics": false,
+            "diagnosticsDelay": 500,
+            "formatter": { "spaces": 2 },
+            "logLevel": "warn",
+            "clientLogLevel": "error",
+        })).unwrap();
+        assert_eq!(
+            config,
+            Config {
+                diagnostics: false,
+                diagnostics_delay: 500,
+                formatter: FormatterStyle::Spaces(2),
+                log_level: Some(LogLevel::Warn),
+                client_log_level: LogLevel::Error,
+            }
+        );
+
+        let config: Config = json::from_value(json!({ "formatter": "tabs" })).unwrap();
+        assert_eq!(config.formatter, FormatterStyle::Tabs);
+    }
+}
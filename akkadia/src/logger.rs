@@ -0,0 +1,274 @@
+// Copyright 2017 Mike Lubinets <lubinetsm@yandex.ru>.
+// See the COPYRIGHT file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Server logs. Records are written to stderr and the log file, and forwarded
+//! to the client as `window/logMessage` and `$/logTrace` notifications, since
+//! editors rarely show the stderr of the server.
+
+use chrono;
+use fern;
+use json;
+use log::{self, Log, LogLevel, LogLevelFilter, LogMetadata, LogRecord, MaxLogLevelFilter,
+          SetLoggerError};
+use lsp_data::{LogMessageParams, LogTraceParams, MessageType, TraceOption,
+               NOTIFICATION__LogMessage, NOTIFICATION_LOG_TRACE};
+use server::Output;
+
+use std::cell::Cell;
+use std::cmp;
+use std::fs::OpenOptions;
+use std::io::{stderr, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// How much of the server execution is traced to the client with `$/logTrace`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trace {
+    Off,
+    /// Debug records, without their origin.
+    Messages,
+    /// All records, the message exchange included, with their origin.
+    Verbose,
+}
+
+impl Trace {
+    pub fn new(option: &TraceOption) -> Trace {
+        match *option {
+            TraceOption::Off => Trace::Off,
+            TraceOption::Messages => Trace::Messages,
+            TraceOption::Verbose => Trace::Verbose,
+        }
+    }
+
+    fn filter(self) -> LogLevelFilter {
+        match self {
+            Trace::Off => LogLevelFilter::Off,
+            Trace::Messages => LogLevelFilter::Debug,
+            Trace::Verbose => LogLevelFilter::Trace,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Levels {
+    /// Records written to stderr and the log file.
+    local: LogLevelFilter,
+    /// Level of local records given on the command line.
+    startup: LogLevelFilter,
+    /// Records sent to the client with `window/logMessage`.
+    client: LogLevelFilter,
+    /// Records sent to the client with `$/logTrace`.
+    trace: Trace,
+}
+
+impl Levels {
+    fn max(&self) -> LogLevelFilter {
+        cmp::max(self.local, cmp::max(self.client, self.trace.filter()))
+    }
+}
+
+lazy_static! {
+    // Handle to the global log level, kept to change verbosity at runtime.
+    static ref MAX_LOG_LEVEL: Mutex<Option<MaxLogLevelFilter>> = Mutex::new(None);
+    static ref LEVELS: Mutex<Levels> = Mutex::new(Levels {
+        local: LogLevelFilter::Trace,
+        startup: LogLevelFilter::Trace,
+        client: LogLevelFilter::Info,
+        trace: Trace::Off,
+    });
+    // Sends a message to the client, set while a client is connected.
+    static ref CLIENT: Mutex<Option<Arc<Fn(String) + Send + Sync>>> = Mutex::new(None);
+}
+
+thread_local! {
+    static FORWARDING: Cell<bool> = Cell::new(false);
+}
+
+struct Logger {
+    /// Writes to stderr and the log file.
+    local: Box<Log>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        metadata.level() <= LEVELS.lock().unwrap().max()
+    }
+
+    fn log(&self, record: &LogRecord) {
+        let levels = *LEVELS.lock().unwrap();
+        if record.level() <= levels.local {
+            self.local.log(record);
+        }
+        forward(record, &levels);
+    }
+}
+
+pub fn init(log_file: &Path, level: LogLevelFilter) -> Result<(), SetLoggerError> {
+    // Output logs to stderr by default
+    let mut fern_dispatch = fern::Dispatch::default()
+        .format(|out, message, record| {
+            out.finish(format_args!(
+                "{}[{}:{}][{}] {}",
+                chrono::Local::now().format("[%H:%M:%S]"),
+                record.target(),
+                record.location().line(),
+                record.level(),
+                message
+            ))
+        })
+        // Filtered by `Logger`, the level changes at runtime.
+        .level(LogLevelFilter::Trace)
+        .chain(stderr());
+
+    // Try to open log file
+    let file = OpenOptions::new().append(true).create(true).open(log_file);
+
+    // Chain up log file if it was opened successfuly
+    match file {
+        Ok(file) => fern_dispatch = fern_dispatch.chain(file),
+        Err(err) => {
+            writeln!(
+                stderr(),
+                "Couldn't open log file {}: {}",
+                log_file.display(),
+                err
+            ).unwrap()
+        }
+    }
+
+    // Apply logging configuration
+    let (_, local) = fern_dispatch.into_log();
+    log::set_logger(|max_log_level| {
+        let mut levels = LEVELS.lock().unwrap();
+        levels.local = level;
+        levels.startup = level;
+        max_log_level.set(levels.max());
+        *MAX_LOG_LEVEL.lock().unwrap() = Some(max_log_level);
+        Box::new(Logger { local })
+    })
+}
+
+/// Changes the verbosity of logs written to stderr and the log file.
+pub fn set_level(level: LogLevelFilter) {
+    update(|levels| levels.local = level);
+}
+
+/// Restores the verbosity of local logs given on the command line.
+pub fn reset_level() {
+    update(|levels| levels.local = levels.startup);
+}
+
+/// Changes the verbosity of logs sent to the client with `window/logMessage`.
+pub fn set_client_level(level: LogLevelFilter) {
+    update(|levels| levels.client = level);
+}
+
+/// Changes the verbosity of logs sent to the client with `$/logTrace`.
+pub fn set_trace(trace: Trace) {
+    update(|levels| levels.trace = trace);
+}
+
+fn update<F: FnOnce(&mut Levels)>(f: F) {
+    let mut levels = LEVELS.lock().unwrap();
+    f(&mut levels);
+    // Does nothing if the logger is not set up.
+    if let Some(ref max_log_level) = *MAX_LOG_LEVEL.lock().unwrap() {
+        max_log_level.set(levels.max());
+    }
+}
+
+/// Forwards the logs to the client, until `stop_forwarding` is called.
+pub fn forward_to<O: Output>(out: O) {
+    *CLIENT.lock().unwrap() = Some(Arc::new(move |message| out.response(message)));
+}
+
+pub fn stop_forwarding() {
+    *CLIENT.lock().unwrap() = None;
+}
+
+/// Keeps the records logged by the current thread from being sent to the
+/// client, for the thread writing the messages to it.
+pub fn keep_local() {
+    FORWARDING.with(|forwarding| forwarding.set(true));
+}
+
+/// Stops logging, waiting for the records being written.
+pub fn shutdown() {
+    stop_forwarding();
+    if log::shutdown_logger().is_err() {
+        writeln!(stderr(), "Logger was not set up").unwrap();
+    }
+}
+
+fn forward(record: &LogRecord, levels: &Levels) {
+    let message = if record.level() <= levels.client {
+        log_message(record)
+    } else if record.level() <= levels.trace.filter() {
+        log_trace(record, levels.trace)
+    } else {
+        return;
+    };
+
+    FORWARDING.with(|forwarding| {
+        // Records logged while sending one, e.g. by the output, are only
+        // written locally, or they would be sent forever.
+        if forwarding.get() {
+            return;
+        }
+        // Sending may wait for the output, don't hold the lock meanwhile.
+        let send = CLIENT.lock().unwrap().clone();
+        if let Some(send) = send {
+            forwarding.set(true);
+            send(message);
+            forwarding.set(false);
+        }
+    });
+}
+
+fn log_message(record: &LogRecord) -> String {
+    let typ = match record.level() {
+        LogLevel::Error => MessageType::Error,
+        LogLevel::Warn => MessageType::Warning,
+        LogLevel::Info => MessageType::Info,
+        LogLevel::Debug | LogLevel::Trace => MessageType::Log,
+    };
+    let params = LogMessageParams {
+        typ,
+        message: record.args().to_string(),
+    };
+
+    json::to_string(&json!({
+        "jsonrpc": "2.0",
+        "method": NOTIFICATION__LogMessage,
+        "params": params,
+    })).unwrap()
+}
+
+fn log_trace(record: &LogRecord, trace: Trace) -> String {
+    let verbose = match trace {
+        Trace::Verbose => Some(format!(
+            "[{}:{}][{}]",
+            record.target(),
+            record.location().line(),
+            record.level()
+        )),
+        _ => None,
+    };
+    let params = LogTraceParams {
+        message: record.args().to_string(),
+        verbose,
+    };
+
+    json::to_string(&json!({
+        "jsonrpc": "2.0",
+        "method": NOTIFICATION_LOG_TRACE,
+        "params": params,
+    })).unwrap()
+}
@@ -8,15 +8,20 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::{self, Debug};
 use std::path::PathBuf;
 use std::error::Error;
 
 use url::Url;
-use serde::Serialize;
+use url_serde;
+use json;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error as DeError;
+use serde::ser::Error as SerError;
 use span;
 use vfs::FileContents;
+use config::Config;
 
 pub use lstypes::*;
 use jsonrpc::version;
@@ -26,6 +31,10 @@ pub type Span = span::Span<span::ZeroIndexed>;
 pub const NOTIFICATION_DIAGNOSTICS_BEGIN: &'static str = "akkadiaDocument/diagnosticsBegin";
 pub const NOTIFICATION_DIAGNOSTICS_END: &'static str = "akkadiaDocument/diagnosticsEnd";
 pub const NOTIFICATION_BUILD_BEGIN: &'static str = "akkadiaDocument/beginBuild";
+pub const REQUEST_CONFIGURATION: &'static str = "workspace/configuration";
+pub const NOTIFICATION_LOG_TRACE: &'static str = "$/logTrace";
+pub const REQUEST_WORK_DONE_PROGRESS_CREATE: &'static str = "window/workDoneProgress/create";
+pub const NOTIFICATION_PROGRESS: &'static str = "$/progress";
 
 #[derive(Debug)]
 pub enum UrlFileParseError {
@@ -176,24 +185,375 @@ pub struct InitializationOptions {
     /// Should the build not be triggered immediately after receiving `initialize`
     #[serde(rename = "omitInitBuild")]
     pub omit_init_build: bool,
+    /// Initial settings, the same as sent with `workspace/didChangeConfiguration`
+    pub settings: Option<ChangeConfigSettings>,
 }
 
 impl Default for InitializationOptions {
     fn default() -> Self {
-        InitializationOptions { omit_init_build: false }
+        InitializationOptions {
+            omit_init_build: false,
+            settings: None,
+        }
+    }
+}
+
+/// `InitializeParams` including the fields unknown to `languageserver_types`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct InitializeParams {
+    /// The process Id of the parent process that started the server.
+    #[serde(rename = "processId")]
+    pub process_id: Option<u64>,
+    /// Deprecated in favour of `root_uri`.
+    #[serde(rename = "rootPath")]
+    #[serde(default)]
+    pub root_path: Option<String>,
+    /// The root of the workspace, wins over `root_path` if both are set.
+    #[serde(rename = "rootUri")]
+    #[serde(default)]
+    pub root_uri: Option<url_serde::Serde<Url>>,
+    /// User provided initialization options.
+    #[serde(rename = "initializationOptions")]
+    pub initialization_options: Option<json::Value>,
+    /// The capabilities provided by the client (editor), see `ClientFeatures`
+    pub capabilities: json::Value,
+    /// The initial trace setting. If omitted trace is disabled ('off').
+    #[serde(default)]
+    pub trace: TraceOption,
+    /// Folders open in the editor, wins over `root_uri` if set.
+    #[serde(rename = "workspaceFolders")]
+    #[serde(default)]
+    pub workspace_folders: Option<Vec<WorkspaceFolder>>,
+}
+
+/// Optional protocol features supported by the client.
+///
+/// Read from raw client capabilities, since most of them are unknown
+/// to `languageserver_types`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ClientFeatures {
+    /// Completion items can be snippets.
+    pub snippets: bool,
+    /// Completion item documentation can be Markdown.
+    pub completion_markdown: bool,
+    /// Document symbols can be nested.
+    pub hierarchical_symbols: bool,
+    /// Watched files can be registered with `client/registerCapability`.
+    pub watched_files_registration: bool,
+    /// Settings can be pulled with `workspace/configuration`.
+    pub configuration: bool,
+    /// Progress can be reported with `window/workDoneProgress/create`.
+    pub work_done_progress: bool,
+}
+
+impl ClientFeatures {
+    pub fn new(capabilities: &json::Value) -> ClientFeatures {
+        let flag = |pointer: &str| {
+            capabilities
+                .pointer(pointer)
+                .and_then(|value| value.as_bool())
+                .unwrap_or(false)
+        };
+        let completion_markdown = capabilities
+            .pointer("/textDocument/completion/completionItem/documentationFormat")
+            .and_then(|formats| formats.as_array())
+            .map_or(false, |formats| {
+                formats.iter().any(|format| format == "markdown")
+            });
+
+        ClientFeatures {
+            snippets: flag("/textDocument/completion/completionItem/snippetSupport"),
+            completion_markdown,
+            hierarchical_symbols: flag(
+                "/textDocument/documentSymbol/hierarchicalDocumentSymbolSupport",
+            ),
+            watched_files_registration: flag(
+                "/workspace/didChangeWatchedFiles/dynamicRegistration",
+            ),
+            configuration: flag("/workspace/configuration"),
+            work_done_progress: flag("/window/workDoneProgress"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct WorkspaceFolder {
+    #[serde(with = "url_serde")]
+    pub uri: Url,
+    pub name: String,
+}
+
+/// Parameters of the `workspace/didChangeWorkspaceFolders` notification.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct DidChangeWorkspaceFoldersParams {
+    pub event: WorkspaceFoldersChangeEvent,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct WorkspaceFoldersChangeEvent {
+    pub added: Vec<WorkspaceFolder>,
+    pub removed: Vec<WorkspaceFolder>,
+}
+
+/// `InitializeResult` including the capabilities unknown to `languageserver_types`.
+#[derive(Debug)]
+pub struct InitializeResult {
+    pub capabilities: ServerCapabilities,
+    pub workspace: WorkspaceServerCapabilities,
+}
+
+impl Serialize for InitializeResult {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut capabilities = json::to_value(&self.capabilities).map_err(S::Error::custom)?;
+        let workspace = json::to_value(&self.workspace).map_err(S::Error::custom)?;
+        if let Some(capabilities) = capabilities.as_object_mut() {
+            capabilities.insert("workspace".to_owned(), workspace);
+        }
+        json!({ "capabilities": capabilities }).serialize(serializer)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct WorkspaceServerCapabilities {
+    #[serde(rename = "workspaceFolders")]
+    pub workspace_folders: WorkspaceFoldersServerCapabilities,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WorkspaceFoldersServerCapabilities {
+    pub supported: bool,
+    #[serde(rename = "changeNotifications")]
+    pub change_notifications: bool,
+}
+
+/// `CompletionItem` including the fields unknown to `languageserver_types`.
+#[derive(Debug, PartialEq, Default, Deserialize, Serialize)]
+pub struct CompletionItem {
+    pub label: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<CompletionItemKind>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub documentation: Option<Documentation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "insertText")]
+    pub insert_text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "insertTextFormat")]
+    pub insert_text_format: Option<InsertTextFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<json::Value>,
+}
+
+impl CompletionItem {
+    /// Create a CompletionItem with the minimum possible info (label and detail).
+    pub fn new_simple(label: String, detail: String) -> CompletionItem {
+        CompletionItem {
+            label,
+            detail: Some(detail),
+            ..CompletionItem::default()
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum InsertTextFormat {
+    PlainText = 1,
+    Snippet = 2,
+}
+
+impl Serialize for InsertTextFormat {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(*self as u8)
+    }
+}
+
+impl<'de> Deserialize<'de> for InsertTextFormat {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match u8::deserialize(deserializer)? {
+            1 => Ok(InsertTextFormat::PlainText),
+            2 => Ok(InsertTextFormat::Snippet),
+            i => Err(D::Error::custom(format!("invalid insert text format: {}", i))),
+        }
+    }
+}
+
+/// Documentation is either plain text or markup, depending on the client.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Documentation {
+    String(String),
+    MarkupContent(MarkupContent),
+}
+
+impl Documentation {
+    /// Uses `markdown` if the client can render it and `plain` otherwise.
+    pub fn new(markdown: bool, markdown_text: &str, plain_text: &str) -> Documentation {
+        if markdown {
+            Documentation::MarkupContent(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: markdown_text.to_owned(),
+            })
+        } else {
+            Documentation::String(plain_text.to_owned())
+        }
     }
 }
 
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct MarkupContent {
+    pub kind: MarkupKind,
+    pub value: String,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize, Serialize)]
+pub enum MarkupKind {
+    #[serde(rename = "plaintext")]
+    PlainText,
+    #[serde(rename = "markdown")]
+    Markdown,
+}
+
+/// Settings of the `workspace/didChangeConfiguration` notification.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct ChangeConfigSettings {
+    pub akkadia: Config,
+}
+
+/// Parameters of the `workspace/configuration` request.
+#[derive(Debug, Serialize)]
+pub struct ConfigurationParams {
+    pub items: Vec<ConfigurationItem>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConfigurationItem {
+    /// The resource the settings are scoped to, the whole workspace if missing.
+    #[serde(rename = "scopeUri")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope_uri: Option<url_serde::Serde<Url>>,
+    /// The configuration section asked for.
+    pub section: String,
+}
+
+/// Parameters of the `$/setTrace` notification.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SetTraceParams {
+    pub value: TraceOption,
+}
+
+/// Parameters of the `$/logTrace` notification.
+#[derive(Debug, Serialize)]
+pub struct LogTraceParams {
+    pub message: String,
+    /// Details shown when the trace is verbose.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verbose: Option<String>,
+}
+
+/// Parameters of the `window/workDoneProgress/create` request.
+#[derive(Debug, Serialize)]
+pub struct WorkDoneProgressCreateParams {
+    pub token: String,
+}
+
+/// Parameters of the `$/progress` notification.
+#[derive(Debug, Serialize)]
+pub struct ProgressParams {
+    pub token: String,
+    pub value: WorkDoneProgress,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "kind")]
+pub enum WorkDoneProgress {
+    #[serde(rename = "begin")]
+    Begin {
+        title: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+        /// From 0 to 100.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        percentage: Option<u32>,
+    },
+    #[serde(rename = "report")]
+    Report {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        percentage: Option<u32>,
+    },
+    #[serde(rename = "end")]
+    End {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+}
+
+/// Result of the `akkadia/serverStatus` request.
+#[derive(Debug, Serialize)]
+pub struct ServerStatus {
+    /// Requests handled so far, by method.
+    pub requests: BTreeMap<String, MethodStatus>,
+    pub vfs: VfsStatus,
+    /// Files with a cached analysis.
+    #[serde(rename = "cachedAnalyses")]
+    pub cached_analyses: usize,
+    /// Requests read but not answered yet.
+    #[serde(rename = "queueDepth")]
+    pub queue_depth: usize,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct MethodStatus {
+    pub count: u64,
+    /// Latency percentiles over the last requests, from when a request is
+    /// read to when it is answered.
+    #[serde(rename = "p50Ms")]
+    pub p50_ms: f64,
+    #[serde(rename = "p90Ms")]
+    pub p90_ms: f64,
+    #[serde(rename = "p99Ms")]
+    pub p99_ms: f64,
+    #[serde(rename = "maxMs")]
+    pub max_ms: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VfsStatus {
+    /// Files held in memory.
+    pub files: usize,
+    /// Size of their text.
+    pub bytes: usize,
+}
+
+/// Parameters of the `akkadia/syntaxTree` request.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SyntaxTreeParams {
+    #[serde(rename = "textDocument")]
+    pub text_document: TextDocumentIdentifier,
+    /// Only show the smallest node spanning the range, the whole document
+    /// if missing.
+    pub range: Option<Range>,
+}
+
 /// An event-like (no response needed) notification message.
 #[derive(Debug, Serialize)]
-pub struct NotificationMessage {
+pub struct NotificationMessage<T>
+where
+    T: Debug + Serialize,
+{
     jsonrpc: version::Version,
     pub method: &'static str,
-    pub params: Option<PublishDiagnosticsParams>,
+    pub params: Option<T>,
 }
 
-impl NotificationMessage {
-    pub fn new(method: &'static str, params: Option<PublishDiagnosticsParams>) -> Self {
+impl<T> NotificationMessage<T>
+where
+    T: Debug + Serialize,
+{
+    pub fn new(method: &'static str, params: Option<T>) -> Self {
         NotificationMessage {
             jsonrpc: version::Version::V2,
             method,
@@ -7,6 +7,7 @@ extern crate lazy_static;
 extern crate log;
 extern crate fern;
 extern crate chrono;
+extern crate backtrace;
 
 #[macro_use]
 extern crate serde_derive;
@@ -17,68 +18,83 @@ extern crate serde_json as json;
 extern crate jsonrpc_core as jsonrpc;
 extern crate languageserver_types as lstypes;
 extern crate url;
+extern crate url_serde;
 
 extern crate akkadia_span as span;
 extern crate akkadia_vfs as vfs;
 
+mod analysis;
+mod cli;
+mod config;
+mod logger;
 mod lsp_data;
+mod project;
 mod server;
+mod syntax;
 mod test;
 mod actions;
 
-static AKKADIA_LOG_FILE: &str = ".akkadia.log";
-
 use vfs::Vfs;
+use cli::Command;
+use std::env;
+use std::io::{stderr, Write};
+use std::path::Path;
+use std::process;
 use std::sync::Arc;
 
 fn main() {
-    init_logger().unwrap();
-
-    let vfs = Arc::new(Vfs::new());
+    let options = match cli::parse(env::args().skip(1)) {
+        Ok(Command::Run(options)) => options,
+        Ok(Command::Version) => {
+            println!("akkadia {}", version());
+            return;
+        }
+        Ok(Command::Help) => {
+            println!("{}", cli::USAGE);
+            return;
+        }
+        Err(e) => {
+            writeln!(stderr(), "akkadia: {}\n\n{}", e, cli::USAGE).unwrap();
+            process::exit(2);
+        }
+    };
 
-    server::run_server(vfs);
-}
+    logger::init(&options.log_file, options.log_level).unwrap();
+    server::panic::install_hook();
 
-use std::io::Write;
-use std::io::stderr;
-use std::fs::OpenOptions;
+    let vfs = Arc::new(Vfs::new());
 
-fn init_logger() -> Result<(), log::SetLoggerError> {
-    // Output logs to stderr by default
-    let mut fern_dispatch = fern::Dispatch::default()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "{}[{}:{}][{}] {}",
-                chrono::Local::now().format("[%H:%M:%S]"),
-                record.target(),
-                record.location().line(),
-                record.level(),
-                message
-            ))
-        })
-        .level(log::LogLevelFilter::Trace)
-        .chain(stderr());
+    let exit_code = match options.replay {
+        Some(ref trace) => replay(vfs, trace),
+        None => {
+            let record = options.record.as_ref().map(|path| path.as_path());
+            match server::run_server(vfs, &options.transport, record) {
+                Ok(exit_code) => exit_code,
+                Err(e) => {
+                    error!("Couldn't serve on {}: {}", options.transport, e);
+                    1
+                }
+            }
+        }
+    };
 
-    // Try to open log file
-    let log_file = OpenOptions::new().append(true).create(true).open(
-        AKKADIA_LOG_FILE,
-    );
+    logger::shutdown();
+    process::exit(exit_code);
+}
 
-    // Chain up log file if it was opened successfuly
-    match log_file {
-        Ok(file) => fern_dispatch = fern_dispatch.chain(file),
-        Err(err) => {
-            writeln!(
-                stderr(),
-                "Couldn't open log file {}: {}",
-                AKKADIA_LOG_FILE,
-                err
-            ).unwrap()
+/// Replays the session recorded in `trace`, returns 0 if the server answered
+/// as recorded, 1 otherwise.
+fn replay(vfs: Arc<Vfs>, trace: &Path) -> i32 {
+    match server::record::replay(vfs, trace) {
+        Ok(report) => {
+            println!("{}", report);
+            if report.is_clean() { 0 } else { 1 }
+        }
+        Err(e) => {
+            error!("Couldn't replay {}: {}", trace.display(), e);
+            1
         }
     }
-
-    // Apply logging configuration
-    fern_dispatch.apply()
 }
 
 fn version() -> &'static str {
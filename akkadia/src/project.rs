@@ -0,0 +1,195 @@
+// Copyright 2017 Mike Lubinets <lubinetsm@yandex.ru>.
+// See the COPYRIGHT file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Slang projects opened in the editor, one per workspace folder.
+
+use analysis::Analysis;
+use config::Config;
+use lsp_data::{parse_file_path, UrlFileParseError, WorkspaceFolder};
+use server::panic::lock;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Extension of Slang sources.
+const SOURCE_EXTENSION: &'static str = "slang";
+
+pub struct Project {
+    pub name: String,
+    pub root: PathBuf,
+    /// Cached analysis of the files of the project.
+    pub analysis: Arc<Analysis>,
+    /// Settings scoped to the root of the project.
+    pub config: Mutex<Config>,
+}
+
+impl Project {
+    pub fn new(root: PathBuf) -> Project {
+        let name = root.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| root.display().to_string());
+        Project::with_name(name, root)
+    }
+
+    pub fn from_folder(folder: &WorkspaceFolder) -> Result<Project, UrlFileParseError> {
+        parse_file_path(&folder.uri).map(|root| Project::with_name(folder.name.clone(), root))
+    }
+
+    fn with_name(name: String, root: PathBuf) -> Project {
+        Project {
+            name,
+            root,
+            analysis: Arc::new(Analysis::new()),
+            config: Mutex::new(Config::default()),
+        }
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        path.starts_with(&self.root)
+    }
+
+    /// Returns the Slang sources under the root of the project, sorted.
+    /// Hidden directories, such as `.git`, and symlinks to directories, which
+    /// may form cycles, are skipped.
+    pub fn source_files(&self) -> Vec<PathBuf> {
+        let mut files = vec![];
+        if let Err(e) = find_sources(&self.root, &mut files) {
+            debug!("Couldn't list the sources of {}: {}", self.name, e);
+        }
+        files.sort();
+        files
+    }
+}
+
+fn find_sources(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let hidden = path.file_name()
+            .map_or(false, |name| name.to_string_lossy().starts_with('.'));
+        if hidden {
+            continue;
+        }
+
+        if entry.file_type()?.is_dir() {
+            find_sources(&path, files)?;
+        } else if path.extension().map_or(false, |ext| ext == SOURCE_EXTENSION) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// All projects of the workspace. Folders may be nested, a file belongs to
+/// the innermost project containing it.
+pub struct Workspace {
+    projects: Mutex<Vec<Arc<Project>>>,
+}
+
+impl Workspace {
+    pub fn new(projects: Vec<Project>) -> Workspace {
+        let workspace = Workspace { projects: Mutex::new(vec![]) };
+        for project in projects {
+            workspace.add(project);
+        }
+        workspace
+    }
+
+    /// Adds a project, replacing the one with the same root if any.
+    pub fn add(&self, project: Project) -> Arc<Project> {
+        let project = Arc::new(project);
+        let mut projects = lock(&self.projects);
+        projects.retain(|p| p.root != project.root);
+        projects.push(project.clone());
+        project
+    }
+
+    pub fn remove(&self, root: &Path) -> Option<Arc<Project>> {
+        let mut projects = lock(&self.projects);
+        let index = projects.iter().position(|p| p.root == root);
+        index.map(|index| projects.remove(index))
+    }
+
+    /// Returns the innermost project containing `path`.
+    pub fn project_for(&self, path: &Path) -> Option<Arc<Project>> {
+        let projects = lock(&self.projects);
+        projects
+            .iter()
+            .filter(|p| p.contains(path))
+            .max_by_key(|p| p.root.components().count())
+            .cloned()
+    }
+
+    pub fn projects(&self) -> Vec<Arc<Project>> {
+        lock(&self.projects).clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_project_for() {
+        let workspace = Workspace::new(vec![
+            Project::new(PathBuf::from("/ws/app")),
+            Project::new(PathBuf::from("/ws/app/vendor/lib")),
+            Project::new(PathBuf::from("/ws/tools")),
+        ]);
+
+        let root_of = |path: &str| workspace.project_for(Path::new(path)).map(|p| p.root.clone());
+
+        assert_eq!(root_of("/ws/app/src/main.slang"), Some(PathBuf::from("/ws/app")));
+        assert_eq!(
+            root_of("/ws/app/vendor/lib/src/lib.slang"),
+            Some(PathBuf::from("/ws/app/vendor/lib"))
+        );
+        assert_eq!(root_of("/ws/tools/gen.slang"), Some(PathBuf::from("/ws/tools")));
+        assert_eq!(root_of("/ws/application/main.slang"), None);
+
+        workspace.remove(Path::new("/ws/app/vendor/lib"));
+        assert_eq!(
+            root_of("/ws/app/vendor/lib/src/lib.slang"),
+            Some(PathBuf::from("/ws/app"))
+        );
+        assert_eq!(workspace.projects().len(), 2);
+
+        assert_eq!(Project::new(PathBuf::from("/ws/tools")).name, "tools");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_source_files_symlink_cycle() {
+        use std::env;
+        use std::os::unix::fs::symlink;
+
+        let root = env::temp_dir().join("akkadia-test-symlink-cycle");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::File::create(root.join("src/main.slang")).unwrap();
+        symlink(&root, root.join("src/loop")).unwrap();
+
+        let files = Project::new(root.clone()).source_files();
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(files, vec![root.join("src/main.slang")]);
+    }
+
+    #[test]
+    fn test_source_files() {
+        let root = ::std::env::current_dir().unwrap().join("test_data").join("common");
+        let project = Project::new(root.clone());
+        assert_eq!(project.source_files(), vec![root.join("src").join("main.slang")]);
+
+        let missing = Project::new(root.join("missing"));
+        assert!(missing.source_files().is_empty());
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright 2017 Mike Lubinets <lubinetsm@yandex.ru>.
+// See the COPYRIGHT file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Cancellation of requests by `$/cancelRequest`.
+
+use actions::notifications::Cancel;
+use server::Action;
+use jsonrpc::Id;
+use json;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Error code of the response to a cancelled request.
+pub const REQUEST_CANCELLED: i64 = -32800;
+
+/// Set once the client is no longer interested in the result of a request.
+/// Long running handlers should check it and give up early.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Tokens of the requests that have been read but not answered yet.
+#[derive(Debug, Clone, Default)]
+pub struct PendingRequests {
+    tokens: Arc<Mutex<HashMap<Id, CancelToken>>>,
+}
+
+impl PendingRequests {
+    pub fn new() -> PendingRequests {
+        PendingRequests::default()
+    }
+
+    /// Returns the token of request `id`, registering the request if needed.
+    pub fn register(&self, id: Id) -> CancelToken {
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.entry(id).or_insert_with(CancelToken::new).clone()
+    }
+
+    /// Cancels request `id`. Requests that are already answered are ignored.
+    pub fn cancel(&self, id: &Id) {
+        if let Some(token) = self.tokens.lock().unwrap().get(id) {
+            token.cancel();
+        }
+    }
+
+    /// Number of requests read but not answered yet.
+    pub fn len(&self) -> usize {
+        self.tokens.lock().unwrap().len()
+    }
+
+    /// Forgets request `id` once it is answered.
+    pub fn finish(&self, id: &Id) {
+        self.tokens.lock().unwrap().remove(id);
+    }
+
+    /// Registers requests and applies cancellations as soon as a message is
+    /// read, before the messages queued ahead of it are handled.
+    pub fn track(&self, msg: &str) {
+        let msg: json::Value = match json::from_str(msg) {
+            Ok(msg) => msg,
+            // Reported when the message is handled.
+            Err(_) => return,
+        };
+
+        let method = match msg.get("method").and_then(|method| method.as_str()) {
+            Some(method) => method,
+            None => return,
+        };
+
+        if method == Cancel::METHOD {
+            let id = msg.pointer("/params/id")
+                .and_then(|id| json::from_value(id.to_owned()).ok());
+            if let Some(id) = id {
+                trace!("Cancelling request {:?}", id);
+                self.cancel(&id);
+            }
+        } else if let Some(id) = request_id(&msg) {
+            self.register(id);
+        }
+    }
+
+    /// Forgets the request registered by `track` for a message that is
+    /// dropped before it is dispatched, such as a malformed one.
+    pub fn untrack(&self, msg: &str) {
+        let msg: json::Value = match json::from_str(msg) {
+            Ok(msg) => msg,
+            Err(_) => return,
+        };
+
+        if let Some(id) = request_id(&msg) {
+            self.finish(&id);
+        }
+    }
+}
+
+fn request_id(msg: &json::Value) -> Option<Id> {
+    msg.get("id").and_then(|id| json::from_value(id.to_owned()).ok())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_track_cancellation() {
+        let pending = PendingRequests::new();
+        pending.track(r#"{"jsonrpc":"2.0","id":1,"method":"textDocument/completion","params":{}}"#);
+        pending.track(r#"{"jsonrpc":"2.0","id":"2","method":"textDocument/completion","params":{}}"#);
+        pending.track(r#"{"jsonrpc":"2.0","method":"$/cancelRequest","params":{"id":1}}"#);
+
+        assert!(pending.register(Id::Num(1)).is_cancelled());
+        assert!(!pending.register(Id::Str("2".to_owned())).is_cancelled());
+
+        // Cancelling answered requests doesn't leave anything behind.
+        pending.finish(&Id::Num(1));
+        pending.track(r#"{"jsonrpc":"2.0","method":"$/cancelRequest","params":{"id":1}}"#);
+        assert!(!pending.register(Id::Num(1)).is_cancelled());
+    }
+
+    #[test]
+    fn test_untrack() {
+        let pending = PendingRequests::new();
+        let msg = r#"{"jsonrpc":"2.0","id":1,"method":"textDocument/completion","params":5}"#;
+        pending.track(msg);
+        assert_eq!(pending.len(), 1);
+
+        pending.untrack(msg);
+        assert_eq!(pending.len(), 0);
+    }
+}
@@ -0,0 +1,63 @@
+// Copyright 2017 Mike Lubinets <lubinetsm@yandex.ru>.
+// See the COPYRIGHT file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Failures of request handlers, answered with a JSON-RPC error.
+
+use jsonrpc::{self, ErrorCode};
+use server::cancel::REQUEST_CANCELLED;
+
+/// Error codes defined by the LSP on top of JSON-RPC.
+const SERVER_NOT_INITIALIZED: i64 = -32002;
+const CONTENT_MODIFIED: i64 = -32801;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResponseError {
+    /// The request isn't allowed in the current state, e.g. a second `initialize`.
+    InvalidRequest(String),
+    /// The request doesn't make sense, e.g. it points to an unknown document.
+    InvalidParams(String),
+    /// Something went wrong on our side.
+    Internal(String),
+    /// The document changed while the request was handled, the result would
+    /// be outdated.
+    ContentModified,
+    /// The request came before `initialize`.
+    ServerNotInitialized,
+    /// The client cancelled the request.
+    Cancelled,
+}
+
+impl ResponseError {
+    pub fn to_rpc_error(self) -> jsonrpc::Error {
+        let (code, message) = match self {
+            ResponseError::InvalidRequest(message) => (ErrorCode::InvalidRequest, message),
+            ResponseError::InvalidParams(message) => (ErrorCode::InvalidParams, message),
+            ResponseError::Internal(message) => (ErrorCode::InternalError, message),
+            ResponseError::ContentModified => (
+                ErrorCode::ServerError(CONTENT_MODIFIED),
+                "Content modified".to_owned(),
+            ),
+            ResponseError::ServerNotInitialized => (
+                ErrorCode::ServerError(SERVER_NOT_INITIALIZED),
+                "Server not initialized".to_owned(),
+            ),
+            ResponseError::Cancelled => (
+                ErrorCode::ServerError(REQUEST_CANCELLED),
+                "Request cancelled".to_owned(),
+            ),
+        };
+
+        jsonrpc::Error {
+            code,
+            message,
+            data: None,
+        }
+    }
+}
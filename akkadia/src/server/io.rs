@@ -19,13 +19,15 @@
 // except according to those terms.
 
 use json;
+use logger;
 
 use lsp_data::*;
 
 use std::fmt;
-use std::io::{self, Read, Write};
-use std::sync::Arc;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::{mpsc, Arc, Mutex};
 use std::sync::atomic::{Ordering, AtomicU32};
+use std::thread;
 
 use jsonrpc::{self, Id, response, version};
 
@@ -39,69 +41,218 @@ pub(super) struct StdioMsgReader;
 
 impl MessageReader for StdioMsgReader {
     fn read_message(&self) -> Option<String> {
-        macro_rules! handle_err {
-            ($e: expr, $s: expr) => {
-                match $e {
-                    Ok(x) => x,
-                    Err(_) => {
-                        debug!($s);
-                        return None;
-                    }
-                }
+        let stdin = io::stdin();
+        let mut input = stdin.lock();
+        read_message(&mut input)
+    }
+}
+
+/// Reads messages from a socket, or any other byte stream.
+pub(super) struct StreamMsgReader<R> {
+    input: Mutex<BufReader<R>>,
+}
+
+impl<R: Read> StreamMsgReader<R> {
+    pub fn new(input: R) -> StreamMsgReader<R> {
+        StreamMsgReader { input: Mutex::new(BufReader::new(input)) }
+    }
+}
+
+impl<R: Read> MessageReader for StreamMsgReader<R> {
+    fn read_message(&self) -> Option<String> {
+        read_message(&mut *self.input.lock().unwrap())
+    }
+}
+
+/// Messages larger than this are skipped.
+const MAX_CONTENT_LENGTH: usize = 64 * 1024 * 1024;
+/// Messages with longer header lines, or more headers, are skipped.
+const MAX_HEADER_LENGTH: usize = 8 * 1024;
+const MAX_HEADERS: usize = 32;
+
+#[derive(Debug)]
+enum FrameError {
+    /// The input is closed or broken, no more messages can be read.
+    Closed(String),
+    /// The message is malformed, the ones following it can still be read.
+    Malformed(String),
+}
+
+/// Reads the next message framed by the LSP base protocol, skipping the
+/// malformed ones. Returns `None` once the input is closed.
+pub fn read_message<R: BufRead>(input: &mut R) -> Option<String> {
+    loop {
+        match read_frame(input) {
+            Ok(content) => return Some(content),
+            Err(FrameError::Malformed(e)) => debug!("Skipping malformed message: {}", e),
+            Err(FrameError::Closed(e)) => {
+                debug!("Can't read messages anymore: {}", e);
+                return None;
             }
         }
+    }
+}
 
-        // Read in the "Content-length: xx" part
-        let mut buffer = String::new();
-        handle_err!(
-            io::stdin().read_line(&mut buffer),
-            "Could not read from stdin"
-        );
+fn read_frame<R: BufRead>(input: &mut R) -> Result<String, FrameError> {
+    let headers = read_headers(input)?;
 
-        if buffer.is_empty() {
-            debug!("Header is empty");
-            return None;
+    let mut length = None;
+    let mut error = None;
+    for (name, value) in headers {
+        match &name[..] {
+            "content-length" => match value.parse::<usize>() {
+                Ok(value) => length = Some(value),
+                Err(_) => error = Some(format!("invalid Content-Length `{}`", value)),
+            },
+            "content-type" => if let Some(charset) = charset(&value) {
+                if charset != "utf-8" && charset != "utf8" {
+                    error = Some(format!("unsupported charset `{}`", charset));
+                }
+            },
+            _ => trace!("Ignoring header `{}: {}`", name, value),
         }
+    }
 
-        let res: Vec<&str> = buffer.split(' ').collect();
+    // Without a length the content can't be skipped, the next header block
+    // is found when reading the following headers.
+    let length = match length {
+        Some(length) => length,
+        None => {
+            let error = error.unwrap_or_else(|| "missing Content-Length".to_owned());
+            return Err(FrameError::Malformed(error));
+        }
+    };
+    if length > MAX_CONTENT_LENGTH {
+        error = Some(format!("message of {} bytes is too large", length));
+    }
 
-        // Make sure we see the correct header
-        if res.len() != 2 {
-            debug!("Header is malformed");
-            return None;
+    if let Some(error) = error {
+        let skipped = io::copy(&mut input.take(length as u64), &mut io::sink())
+            .map_err(|e| FrameError::Closed(e.to_string()))?;
+        if skipped < length as u64 {
+            return Err(FrameError::Closed("unexpected end of input".to_owned()));
         }
+        return Err(FrameError::Malformed(error));
+    }
+
+    trace!("reading: {} bytes", length);
+    let mut content = vec![0; length];
+    input
+        .read_exact(&mut content)
+        .map_err(|e| FrameError::Closed(e.to_string()))?;
 
-        if res[0].to_lowercase() != "content-length:" {
-            debug!("Header is missing 'content-length'");
-            return None;
+    String::from_utf8(content).map_err(|_| FrameError::Malformed("non-UTF-8 content".to_owned()))
+}
+
+/// Reads headers up to the empty line separating them from the content.
+/// Names are lowercased, as they are case-insensitive.
+fn read_headers<R: BufRead>(input: &mut R) -> Result<Vec<(String, String)>, FrameError> {
+    let mut headers = vec![];
+    let mut malformed = None;
+
+    loop {
+        let mut buffer = vec![];
+        let read = input
+            .by_ref()
+            .take(MAX_HEADER_LENGTH as u64)
+            .read_until(b'\n', &mut buffer)
+            .map_err(|e| FrameError::Closed(e.to_string()))?;
+        if read == 0 {
+            return Err(FrameError::Closed("end of input".to_owned()));
+        }
+        if read == MAX_HEADER_LENGTH && !buffer.ends_with(b"\n") {
+            skip_line(input).map_err(|e| FrameError::Closed(e.to_string()))?;
+            malformed = Some(format!("header longer than {} bytes", MAX_HEADER_LENGTH));
+            continue;
         }
 
-        let size = handle_err!(
-            usize::from_str_radix(&res[1].trim(), 10),
-            "Couldn't read size"
-        );
-        trace!("reading: {} bytes", size);
+        let line = String::from_utf8_lossy(&buffer);
+        let mut line = line.trim_right_matches(|c| c == '\r' || c == '\n');
 
-        // Skip the new lines
-        let mut tmp = String::new();
-        handle_err!(io::stdin().read_line(&mut tmp), "Could not read from stdin");
+        if line.trim().is_empty() {
+            // Tolerate empty lines between messages.
+            if headers.is_empty() && malformed.is_none() {
+                continue;
+            }
+            return match malformed {
+                Some(error) => Err(FrameError::Malformed(error)),
+                None => Ok(headers),
+            };
+        }
 
-        let mut content = vec![0; size];
-        handle_err!(
-            io::stdin().read_exact(&mut content),
-            "Could not read from stdin"
-        );
+        // The content of a message we couldn't skip runs into the headers of
+        // the next one, start over from them.
+        let start = line.to_lowercase().find("content-length:");
+        if let Some(start) = start.and_then(|start| {
+            if start > 0 && line.is_char_boundary(start) {
+                Some(start)
+            } else {
+                None
+            }
+        }) {
+            debug!("Skipping `{}`", &line[..start]);
+            line = &line[start..];
+            headers.clear();
+            malformed = None;
+        }
 
-        let content = handle_err!(String::from_utf8(content), "Non-utf8 input");
+        match line.find(':') {
+            Some(_) if headers.len() == MAX_HEADERS => {
+                malformed = Some(format!("more than {} headers", MAX_HEADERS));
+            }
+            Some(colon) => headers.push((
+                line[..colon].trim().to_lowercase(),
+                line[colon + 1..].trim().to_owned(),
+            )),
+            None => malformed = Some(format!("invalid header `{}`", line)),
+        }
+    }
+}
 
-        Some(content)
+/// Discards the input up to the end of the current line.
+fn skip_line<R: BufRead>(input: &mut R) -> io::Result<()> {
+    loop {
+        let (found, length) = {
+            let buffer = input.fill_buf()?;
+            if buffer.is_empty() {
+                return Ok(());
+            }
+            match buffer.iter().position(|&byte| byte == b'\n') {
+                Some(end) => (true, end + 1),
+                None => (false, buffer.len()),
+            }
+        };
+        input.consume(length);
+        if found {
+            return Ok(());
+        }
     }
 }
 
+/// Returns the lowercased charset of a `Content-Type` header value.
+fn charset(content_type: &str) -> Option<String> {
+    content_type
+        .split(';')
+        .skip(1)
+        .filter_map(|param| {
+            let mut param = param.splitn(2, '=');
+            match (param.next(), param.next()) {
+                (Some(name), Some(value)) if name.trim().to_lowercase() == "charset" => {
+                    Some(value.trim().trim_matches('"').to_lowercase())
+                }
+                _ => None,
+            }
+        })
+        .next()
+}
+
 pub trait Output: Sync + Send + Clone + 'static {
     fn response(&self, output: String);
     fn provide_id(&self) -> u32;
 
+    /// Blocks until the messages sent so far are written.
+    fn flush(&self) {}
+
     fn failure(&self, id: jsonrpc::Id, error: jsonrpc::Error) {
         let response = response::Failure {
             jsonrpc: Some(version::Version::V2),
@@ -112,16 +263,16 @@ pub trait Output: Sync + Send + Clone + 'static {
         self.response(json::to_string(&response).unwrap());
     }
 
-    fn failure_message<M: Into<String>>(&self, id: usize, code: jsonrpc::ErrorCode, msg: M) {
+    fn failure_message<M: Into<String>>(&self, id: Id, code: jsonrpc::ErrorCode, msg: M) {
         let error = jsonrpc::Error {
             code: code,
             message: msg.into(),
             data: None,
         };
-        self.failure(Id::Num(id as u64), error);
+        self.failure(id, error);
     }
 
-    fn success<D: ::serde::Serialize + fmt::Debug>(&self, id: usize, data: &D) {
+    fn success<D: ::serde::Serialize + fmt::Debug>(&self, id: Id, data: &D) {
         let data = match json::to_string(data) {
             Ok(data) => data,
             Err(e) => {
@@ -134,9 +285,10 @@ pub trait Output: Sync + Send + Clone + 'static {
 
         // {
         //     jsonrpc: String,
-        //     id: usize,
+        //     id: Id,
         //     result: String,
         // }
+        let id = json::to_string(&id).unwrap();
         let output = format!("{{\"jsonrpc\":\"2.0\",\"id\":{},\"result\":{}}}", id, data);
         
         trace!("success output: {:?}", output);
@@ -144,33 +296,218 @@ pub trait Output: Sync + Send + Clone + 'static {
         self.response(output);
     }
 
-    fn notify(&self, notification: NotificationMessage) {
+    fn notify<T: ::serde::Serialize + fmt::Debug>(&self, notification: NotificationMessage<T>) {
         self.response(json::to_string(&notification).unwrap());
     }
 }
 
+/// Messages that can wait for the writer thread before responding blocks.
+const OUTPUT_QUEUE_SIZE: usize = 256;
+
+enum Outgoing {
+    Message(String),
+    /// Answered once the messages queued before are written.
+    Flush(mpsc::Sender<()>),
+}
+
+/// Writes messages to stdout, a socket, or any other byte stream. A single
+/// thread writes them, so that messages sent from different threads don't
+/// interleave.
 #[derive(Clone)]
-pub(super) struct StdioOutput {
+pub(super) struct StreamOutput {
+    queue: Arc<Mutex<mpsc::SyncSender<Outgoing>>>,
     next_id: Arc<AtomicU32>,
 }
 
-impl StdioOutput {
-    pub fn new() -> StdioOutput {
-        StdioOutput { next_id: Arc::new(AtomicU32::new(1)) }
+impl StreamOutput {
+    pub fn new<W: Write + Send + 'static>(output: W) -> StreamOutput {
+        let (queue, receiver) = mpsc::sync_channel(OUTPUT_QUEUE_SIZE);
+        thread::Builder::new()
+            .name("writer".to_owned())
+            .spawn(move || write_messages(output, receiver))
+            .expect("Couldn't spawn the writer thread");
+
+        StreamOutput {
+            queue: Arc::new(Mutex::new(queue)),
+            next_id: Arc::new(AtomicU32::new(1)),
+        }
     }
-}
 
-impl Output for StdioOutput {
-    fn response(&self, output: String) {
-        let o = format!("Content-Length: {}\r\n\r\n{}", output.len(), output);
+    pub fn stdout() -> StreamOutput {
+        StreamOutput::new(io::stdout())
+    }
 
-        trace!("response: {:?}", o);
+    fn send(&self, outgoing: Outgoing) {
+        // The thread only stops once every sender is gone.
+        self.queue.lock().unwrap().send(outgoing).unwrap();
+    }
+}
 
-        print!("{}", o);
-        io::stdout().flush().unwrap();
+impl Output for StreamOutput {
+    fn response(&self, output: String) {
+        trace!("response: {:?}", output);
+        self.send(Outgoing::Message(output));
     }
 
     fn provide_id(&self) -> u32 {
         self.next_id.fetch_add(1, Ordering::SeqCst)
     }
+
+    fn flush(&self) {
+        let (done, written) = mpsc::channel();
+        self.send(Outgoing::Flush(done));
+        let _ = written.recv();
+    }
+}
+
+fn write_messages<W: Write>(mut output: W, receiver: mpsc::Receiver<Outgoing>) {
+    // Sending its logs to the client would block on the queue it empties.
+    logger::keep_local();
+
+    let mut broken = false;
+    for outgoing in receiver {
+        let message = match outgoing {
+            Outgoing::Message(message) => message,
+            Outgoing::Flush(done) => {
+                let _ = done.send(());
+                continue;
+            }
+        };
+        // Keep emptying the queue, so that responding doesn't block.
+        if broken {
+            continue;
+        }
+
+        // The header and the content in a single write.
+        let mut frame = format!("Content-Length: {}\r\n\r\n", message.len()).into_bytes();
+        frame.extend_from_slice(message.as_bytes());
+        if let Err(e) = output.write_all(&frame).and_then(|_| output.flush()) {
+            debug!("Couldn't write response, dropping the next ones: {}", e);
+            broken = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_all<T: AsRef<[u8]>>(input: T) -> Vec<String> {
+        let mut input = input.as_ref();
+        let mut messages = vec![];
+        while let Some(message) = read_message(&mut input) {
+            messages.push(message);
+        }
+        messages
+    }
+
+    #[test]
+    fn test_read_message() {
+        assert_eq!(
+            read_all("Content-Length: 2\r\n\r\n{}Content-Length: 4\r\n\r\nnull"),
+            vec!["{}", "null"]
+        );
+        assert_eq!(
+            read_all(
+                "content-type: application/vscode-jsonrpc; charset=\"UTF-8\"\r\n\
+                 CONTENT-LENGTH:2  \r\n\r\n{}\r\n"
+            ),
+            vec!["{}"]
+        );
+        // Truncated messages end the input.
+        assert!(read_all("Content-Length: 2\r\n").is_empty());
+        assert!(read_all("Content-Length: 8\r\n\r\n{}").is_empty());
+    }
+
+    #[test]
+    fn test_skip_malformed_messages() {
+        let input = [
+            "Content-Length: 2\r\nContent-Type: text/plain; charset=latin1\r\n\r\n{}",
+            "Content-Length: two\r\n\r\n{}",
+            "Content-Length: 1\r\n\r\n1",
+        ].concat();
+        assert_eq!(read_all(&input), vec!["1"]);
+
+        let input = "Garbage\r\n\r\n{}Content-Length: 1\r\n\r\n2";
+        assert_eq!(read_all(input), vec!["2"]);
+
+        let input = b"Content-Length: 2\r\n\r\n\xff\xfeContent-Length: 1\r\n\r\n3";
+        assert_eq!(read_all(&input[..]), vec!["3"]);
+    }
+
+    #[test]
+    fn test_skip_oversized_headers() {
+        let long = format!("X-Padding: {}\r\n", "x".repeat(MAX_HEADER_LENGTH * 2));
+        let input = [
+            "Content-Length: 2\r\n",
+            &long,
+            "\r\n{}",
+            "Content-Length: 1\r\n\r\n4",
+        ].concat();
+        assert_eq!(read_all(&input), vec!["4"]);
+
+        let many = "X-Header: x\r\n".repeat(MAX_HEADERS);
+        let input = [
+            "Content-Length: 2\r\n",
+            &many,
+            "\r\n{}",
+            "Content-Length: 1\r\n\r\n5",
+        ].concat();
+        assert_eq!(read_all(&input), vec!["5"]);
+    }
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_stream_round_trip() {
+        let buffer = Buffer::default();
+        let output = StreamOutput::new(buffer.clone());
+        output.response("{}".to_owned());
+        output.clone().response("null".to_owned());
+        output.flush();
+
+        let written = buffer.0.lock().unwrap().clone();
+        let reader = StreamMsgReader::new(&written[..]);
+        assert_eq!(reader.read_message(), Some("{}".to_owned()));
+        assert_eq!(reader.read_message(), Some("null".to_owned()));
+        assert_eq!(reader.read_message(), None);
+    }
+
+    #[test]
+    fn test_concurrent_responses() {
+        let buffer = Buffer::default();
+        let output = StreamOutput::new(buffer.clone());
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let output = output.clone();
+                thread::spawn(move || for j in 0..100 {
+                    output.response(format!("[{},{}]", i, j));
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        output.flush();
+
+        let written = buffer.0.lock().unwrap().clone();
+        let mut messages = read_all(&written);
+        messages.sort();
+        let mut expected: Vec<String> = (0..8)
+            .flat_map(|i| (0..100).map(move |j| format!("[{},{}]", i, j)))
+            .collect();
+        expected.sort();
+        assert_eq!(messages, expected);
+    }
 }
@@ -15,33 +15,138 @@ use json;
 use serde::Deserialize;
 
 use version;
+use logger::{self, Trace};
 use lsp_data::*;
+use project::Project;
 use actions::ActionContext;
 use actions::notifications;
 use actions::requests;
+use actions::format;
 pub use server::io::{MessageReader, Output};
-use server::io::{StdioMsgReader, StdioOutput};
+pub use server::cancel::{CancelToken, PendingRequests, REQUEST_CANCELLED};
+pub use server::error::ResponseError;
+pub use server::stats::Stats;
+pub use server::transport::Transport;
+use server::io::{StdioMsgReader, StreamMsgReader, StreamOutput};
+use server::panic::catch_panic;
+use server::parent::ParentWatch;
+use server::record::{Recorder, RecordingOutput, RecordingReader};
+use server::pool::WorkerPool;
 
 use std::fmt;
+use std::fs;
 use std::marker::PhantomData;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
+mod cancel;
+mod error;
 mod io;
-
-pub fn run_server(vfs: Arc<Vfs>) {
+pub mod panic;
+mod parent;
+mod pool;
+pub mod record;
+mod stats;
+mod transport;
+
+/// Serves the client until it asks to exit, returns the exit code of the
+/// process: 0 if the client shut the server down first, 1 otherwise.
+/// The session is recorded to the `record` trace file if given.
+pub fn run_server(
+    vfs: Arc<Vfs>,
+    transport: &Transport,
+    record: Option<&Path>,
+) -> ::std::io::Result<i32> {
     debug!(
         "Akkadia Language Server starting up. Version: {}",
         version()
     );
 
-    let service = LsService::new(vfs, Box::new(StdioMsgReader), StdioOutput::new());
-    service.run();
+    let recorder = match record {
+        Some(path) => Some(Recorder::create(path)?),
+        None => None,
+    };
+
+    let exit_code = match *transport {
+        Transport::Stdio => serve(vfs, Box::new(StdioMsgReader), StreamOutput::stdout(), recorder),
+        Transport::Tcp(addr) => {
+            let listener = TcpListener::bind(addr)?;
+            info!("Listening on {}", transport);
+            if !addr.ip().is_loopback() {
+                warn!("{} is reachable from other hosts, anyone there can connect", addr);
+            }
+            let (stream, peer) = listener.accept()?;
+            debug!("Accepted connection from {}", peer);
+
+            let reader = StreamMsgReader::new(stream.try_clone()?);
+            serve(vfs, Box::new(reader), StreamOutput::new(stream), recorder)
+        }
+        #[cfg(unix)]
+        Transport::Unix(ref path) => {
+            let listener = UnixListener::bind(path)?;
+            info!("Listening on {}", transport);
+            let accepted = listener.accept();
+            // Nobody else can connect to the socket anymore.
+            if let Err(e) = fs::remove_file(path) {
+                debug!("Couldn't remove {}: {}", path.display(), e);
+            }
+            let (stream, _) = accepted?;
+            debug!("Accepted connection on {}", path.display());
+
+            let reader = StreamMsgReader::new(stream.try_clone()?);
+            serve(vfs, Box::new(reader), StreamOutput::new(stream), recorder)
+        }
+        #[cfg(not(unix))]
+        Transport::Unix(_) => {
+            return Err(::std::io::Error::new(
+                ::std::io::ErrorKind::Other,
+                "Unix domain sockets are not supported on this platform",
+            ));
+        }
+    };
 
     debug!("Akkadia Language Server shutting down");
+    Ok(exit_code)
+}
+
+/// Serves a single client, which also gets the logs meanwhile.
+fn serve<O: Output>(
+    vfs: Arc<Vfs>,
+    reader: Box<MessageReader + Send + Sync>,
+    output: O,
+    recorder: Option<Recorder>,
+) -> i32 {
+    match recorder {
+        Some(recorder) => {
+            let reader = RecordingReader::new(reader, recorder.clone());
+            serve_client(vfs, Box::new(reader), RecordingOutput::new(output, recorder))
+        }
+        None => serve_client(vfs, reader, output),
+    }
+}
+
+fn serve_client<O: Output>(
+    vfs: Arc<Vfs>,
+    reader: Box<MessageReader + Send + Sync>,
+    output: O,
+) -> i32 {
+    logger::forward_to(output.clone());
+    let exit_code = LsService::new(vfs, reader, output.clone()).run();
+    logger::stop_forwarding();
+    // The process exits right after, write the last responses first.
+    output.flush();
+    exit_code
 }
 
+/// How often unanswered requests to the client are checked for a timeout.
+const EXPIRE_INTERVAL_SECS: u64 = 1;
+
 #[derive(Debug, Serialize)]
 pub struct Ack;
 
@@ -78,16 +183,17 @@ pub trait RequestAction<'a>: Action<'a> {
 
     fn handle<O: Output>(
         &mut self,
-        id: usize,
+        id: Id,
         params: Self::Params,
+        token: &CancelToken,
         ctx: &mut ActionContext,
         out: O,
-    ) -> Result<Self::Response, ()>;
+    ) -> Result<Self::Response, ResponseError>;
 }
 
 
 pub struct Request<'a, A: RequestAction<'a>> {
-    pub id: usize,
+    pub id: Id,
     pub params: A::Params,
     pub _action: PhantomData<A>,
 }
@@ -102,15 +208,45 @@ impl<'a, A: RequestAction<'a>> Request<'a, A> {
     fn dispatch<O: Output>(
         self,
         state: &'a mut LsState,
+        token: CancelToken,
         ctx: &mut ActionContext,
         out: O,
-    ) -> Result<A::Response, ()> {
-        let mut action = A::new(state);
-        let result = action.handle(self.id, self.params, ctx, out.clone())?;
-        if ::std::mem::size_of::<A::Response>() > 0 {
-            out.success(self.id, &result);
+    ) -> Result<A::Response, ResponseError> {
+        let action = A::new(state);
+        self.dispatch_to(action, token, ctx, out)
+    }
+
+    /// Handles the request and answers it, with either the result or the error.
+    fn dispatch_to<O: Output>(
+        self,
+        mut action: A,
+        token: CancelToken,
+        ctx: &mut ActionContext,
+        out: O,
+    ) -> Result<A::Response, ResponseError> {
+        let Request { id, params, .. } = self;
+        let result = (|| {
+            // Cancelled while waiting in the queue, don't bother running it.
+            if token.is_cancelled() {
+                return Err(ResponseError::Cancelled);
+            }
+            if A::METHOD != REQUEST__Initialize && !ctx.is_inited() {
+                return Err(ResponseError::ServerNotInitialized);
+            }
+
+            let result = catch_panic(|| action.handle(id.clone(), params, &token, ctx, out.clone()))
+                .unwrap_or_else(|message| Err(ResponseError::Internal(message)));
+            if token.is_cancelled() {
+                return Err(ResponseError::Cancelled);
+            }
+            result
+        })();
+
+        match result {
+            Ok(ref response) => out.success(id, response),
+            Err(ref e) => out.failure(id, e.clone().to_rpc_error()),
         }
-        Ok(result)
+        result
     }
 }
 
@@ -122,8 +258,11 @@ impl<'a, A: NotificationAction<'a>> Notification<'a, A> {
         out: O,
     ) -> Result<(), ()> {
         let mut action = A::new(state);
-        action.handle(self.params, ctx, out)?;
-        Ok(())
+        let params = self.params;
+        catch_panic(|| action.handle(params, ctx, out)).unwrap_or_else(|message| {
+            debug!("Notification `{}` panicked: {}", A::METHOD, message);
+            Err(())
+        })
     }
 }
 
@@ -149,7 +288,8 @@ impl<'a, A: NotificationAction<'a>> fmt::Display for Notification<'a, A> {
 }
 
 pub struct LsService<O: Output> {
-    msg_reader: Box<MessageReader + Send + Sync>,
+    messages: mpsc::Receiver<Option<Incoming>>,
+    workers: WorkerPool,
     output: O,
     pub ctx: ActionContext,
     pub state: LsState,
@@ -158,6 +298,14 @@ pub struct LsService<O: Output> {
 #[derive(Debug)]
 pub struct LsState {
     shut_down: AtomicBool,
+    /// Set by `exit`, the service stops after the current message.
+    exited: bool,
+    /// The editor that started the server, the service stops once it's gone.
+    parent: ParentWatch,
+    /// Trace asked for by `initialize`, only applied once it is answered.
+    trace: Option<Trace>,
+    pub pending: PendingRequests,
+    pub stats: Stats,
 }
 
 pub struct ShutdownRequest<'a> {
@@ -177,12 +325,14 @@ impl<'a> RequestAction<'a> for ShutdownRequest<'a> {
     type Response = Ack;
     fn handle<O: Output>(
         &mut self,
-        _id: usize,
+        _id: Id,
         _params: Self::Params,
+        _token: &CancelToken,
         ctx: &mut ActionContext,
         _out: O,
-    ) -> Result<Self::Response, ()> {
+    ) -> Result<Self::Response, ResponseError> {
         self.state.shut_down.store(true, Ordering::SeqCst);
+        ctx.shutdown();
         Ok(Ack)
     }
 }
@@ -208,31 +358,38 @@ impl<'a> NotificationAction<'a> for ExitNotification<'a> {
         _ctx: &mut ActionContext,
         _out: O,
     ) -> Result<(), ()> {
-        let shut_down = self.state.shut_down.load(Ordering::SeqCst);
-        ::std::process::exit(if shut_down { 0 } else { 1 });
+        self.state.exited = true;
+        Ok(())
     }
 }
 
-pub struct InitializeRequest;
+pub struct InitializeRequest<'a> {
+    state: &'a mut LsState,
+}
 
-impl<'a> Action<'a> for InitializeRequest {
+impl<'a> Action<'a> for InitializeRequest<'a> {
     type Params = InitializeParams;
     const METHOD: &'static str = "initialize";
 
-    fn new(_: &'a mut LsState) -> Self {
-        InitializeRequest
+    fn new(state: &'a mut LsState) -> Self {
+        InitializeRequest { state }
     }
 }
 
-impl<'a> RequestAction<'a> for InitializeRequest {
-    type Response = ();
+impl<'a> RequestAction<'a> for InitializeRequest<'a> {
+    type Response = InitializeResult;
     fn handle<O: Output>(
         &mut self,
-        id: usize,
+        _id: Id,
         params: Self::Params,
+        _token: &CancelToken,
         ctx: &mut ActionContext,
         out: O,
-    ) -> Result<(), ()> {
+    ) -> Result<Self::Response, ResponseError> {
+        if ctx.is_inited() {
+            return Err(ResponseError::InvalidRequest("server already initialized".to_owned()));
+        }
+
         let init_options: InitializationOptions = params
             .initialization_options
             .as_ref()
@@ -240,31 +397,70 @@ impl<'a> RequestAction<'a> for InitializeRequest {
             .unwrap_or_default();
 
         trace!("init: {:?}", init_options);
+        // Nothing is sent before the response but logs, not traces.
+        self.state.trace = Some(Trace::new(&params.trace));
+
+        let client = ClientFeatures::new(&params.capabilities);
+        trace!("client features: {:?}", client);
+
+        if let Some(pid) = params.process_id {
+            self.state.parent.watch(pid);
+        }
 
         let result = InitializeResult {
+            workspace: WorkspaceServerCapabilities {
+                workspace_folders: WorkspaceFoldersServerCapabilities {
+                    supported: true,
+                    change_notifications: true,
+                },
+            },
             capabilities: ServerCapabilities {
                 text_document_sync: Some(TextDocumentSyncKind::Incremental),
                 completion_provider: Some(CompletionOptions {
                     resolve_provider: Some(true),
                     trigger_characters: vec![".".to_string(), ":".to_string()],
                 }),
+                document_on_type_formatting_provider: Some(DocumentOnTypeFormattingOptions {
+                    first_trigger_character: format::TRIGGER_NEWLINE.to_string(),
+                    more_trigger_character: Some(vec![format::TRIGGER_END.to_string()]),
+                }),
                 ..ServerCapabilities::default()
             }
         };
 
         trace!("capabilities: {:#?}", result);
 
-        out.success(id, &result);
+        let projects = workspace_projects(&params);
+        trace!("projects: {:?}", projects.iter().map(|p| &p.root).collect::<Vec<_>>());
+        ctx.init(projects, client, &init_options, out);
 
-        trace!("responded");
-
-        let root_path = params.root_path.as_ref().map(PathBuf::from).expect("No root path");
-        ctx.init(root_path, &init_options, out);
-
-        Ok(())
+        Ok(result)
     }
 }
 
+/// Returns a project for every workspace folder. Falls back to the root of
+/// the workspace for clients that don't support multiple folders.
+fn workspace_projects(params: &InitializeParams) -> Vec<Project> {
+    if let Some(ref folders) = params.workspace_folders {
+        return folders
+            .iter()
+            .filter_map(|folder| {
+                Project::from_folder(folder)
+                    .map_err(|e| debug!("Ignoring workspace folder {:?}: {}", folder.uri, e))
+                    .ok()
+            })
+            .collect();
+    }
+
+    let root = match params.root_uri {
+        Some(ref uri) => parse_file_path(uri).map_err(|e| {
+            debug!("Ignoring root URI {:?}: {}", **uri, e)
+        }).ok(),
+        None => params.root_path.as_ref().map(PathBuf::from),
+    };
+
+    root.map(Project::new).into_iter().collect()
+}
 
 #[derive(Eq, PartialEq, Debug, Clone, Copy)]
 pub enum ServerStateChange {
@@ -275,34 +471,69 @@ pub enum ServerStateChange {
 impl<O: Output> LsService<O> {
     pub fn new(vfs: Arc<Vfs>, reader: Box<MessageReader + Send + Sync>, output: O) -> LsService<O> {
         let ctx = ActionContext::new(vfs.clone());
+        let pending = PendingRequests::new();
         LsService {
-            msg_reader: reader,
+            messages: read_ahead(reader, pending.clone()),
+            workers: WorkerPool::new(),
             output: output,
             ctx: ctx,
-            state: LsState { shut_down: AtomicBool::new(false) },
+            state: LsState {
+                shut_down: AtomicBool::new(false),
+                exited: false,
+                parent: ParentWatch::new(),
+                trace: None,
+                pending,
+                stats: Stats::new(),
+            },
         }
     }
 
-    pub fn run(mut self) {
+    /// Handles messages until `exit` or the end of input, then waits for the
+    /// requests and the background work being handled. Returns the exit code
+    /// of the process.
+    pub fn run(mut self) -> i32 {
         while self.handle_message() == ServerStateChange::Continue {}
+
+        let exit_code = self.exit_code();
+        self.workers.join();
+        self.ctx.stop();
+        exit_code
+    }
+
+    /// 0 if the client shut the server down before leaving, 1 otherwise.
+    pub fn exit_code(&self) -> i32 {
+        if self.state.shut_down.load(Ordering::SeqCst) {
+            0
+        } else {
+            1
+        }
     }
 
-    fn parse_message(&mut self, msg: &str) -> Result<Option<RawMessage>, jsonrpc::Error> {
+    fn parse_message(
+        &mut self,
+        msg: &str,
+        read_at: Instant,
+    ) -> Result<Option<RawMessage>, jsonrpc::Error> {
         // Parse the message.
         let ls_command: json::Value = json::from_str(msg).map_err(
             |_| jsonrpc::Error::parse_error(),
         )?;
 
         // Per JSON-RPC/LSP spec, Requests must have id, whereas Notifications can't
-        let id = ls_command.get("id").map(|id| {
-            json::from_value(id.to_owned()).unwrap()
-        });
+        let id = match ls_command.get("id") {
+            Some(id) => Some(json::from_value(id.to_owned()).map_err(
+                |_| jsonrpc::Error::invalid_request(),
+            )?),
+            None => None,
+        };
 
         let method = match ls_command.get("method") {
             Some(method) => method,
-            // No method means this is a response to one of our requests. FIXME: we should
-            // confirm these, but currently just ignore them.
-            None => return Ok(None),
+            // No method means this is a response to one of our requests.
+            None => {
+                self.handle_response(&ls_command);
+                return Ok(None);
+            }
         };
 
         let method = method
@@ -323,34 +554,133 @@ impl<O: Output> LsService<O> {
             _ => return Err(jsonrpc::Error::invalid_request()),
         };
 
-        Ok(Some(RawMessage { method, id, params }))
+        Ok(Some(RawMessage {
+            method,
+            id,
+            params,
+            read_at,
+        }))
+    }
+
+    fn handle_response(&mut self, response: &json::Value) {
+        let id = match response.get("id").and_then(|id| id.as_u64()) {
+            Some(id) => id,
+            None => {
+                debug!("response with unexpected id: {:?}", response);
+                return;
+            }
+        };
+
+        let result = match response.get("error") {
+            Some(error) => Err(json::from_value(error.to_owned()).unwrap_or_else(|_| {
+                jsonrpc::Error::invalid_request()
+            })),
+            None => Ok(response.get("result").cloned().unwrap_or(json::Value::Null)),
+        };
+
+        if let Err(message) = catch_panic(|| self.ctx.on_response(id, result)) {
+            debug!("Response to request {} panicked: {}", id, message);
+        }
     }
 
     fn dispatch_message(&mut self, msg: &RawMessage) -> Result<(), jsonrpc::Error> {
         macro_rules! match_action {
-            ($method: expr; notifications: $($n_action: ty),*; requests: $($r_action: ty),*;) => {
+            (
+                $method: expr;
+                notifications: $($n_action: ty),*;
+                requests: $($r_action: ty),*;
+                concurrent_requests: $($c_action: ty),*;
+            ) => {
                 let mut handled = false;
                 trace!("Handling `{}`", $method);
                 $(
                     if $method == <$n_action as Action>::METHOD {
-                        let notification = msg.parse_as_notification::<$n_action>()?;
-                        if let Err(_) = notification.dispatch(&mut self.state, &mut self.ctx, self.output.clone()) {
-                            debug!("Error handling notifcation: {:?}", msg);
+                        // Notifications aren't answered, even if sent with an id.
+                        if let Some(ref id) = msg.id {
+                            self.state.pending.finish(id);
+                        }
+                        // Notifications other than `exit` are dropped until
+                        // the server is initialized.
+                        if !self.ctx.is_inited() && $method != ExitNotification::METHOD {
+                            debug!("Ignoring `{}` before initialization", $method);
+                        } else {
+                            let notification = msg.parse_as_notification::<$n_action>()?;
+                            if let Err(_) = notification.dispatch(&mut self.state, &mut self.ctx, self.output.clone()) {
+                                debug!("Error handling notifcation: {:?}", msg);
+                            }
                         }
                         handled = true;
                     }
                 )*
                 $(
                     if $method == <$r_action as Action>::METHOD {
-                        let request = msg.parse_as_request::<$r_action>()?;
-                        if let Err(_) = request.dispatch(&mut self.state, &mut self.ctx, self.output.clone()) {
-                            debug!("Error handling notifcation: {:?}", msg);
+                        let id = msg.id.clone().unwrap_or(Id::Null);
+                        match msg.parse_as_request::<$r_action>() {
+                            Ok(request) => {
+                                let token = self.state.pending.register(id.clone());
+                                let out = self.output.clone();
+                                if let Err(e) = request.dispatch(&mut self.state, token, &mut self.ctx, out) {
+                                    debug!("Error handling request {:?}: {:?}", msg, e);
+                                }
+                                self.state.stats.record(<$r_action as Action>::METHOD, msg.read_at.elapsed());
+                            }
+                            Err(e) => self.output.failure(id.clone(), e),
                         }
+                        self.state.pending.finish(&id);
+                        handled = true;
+                    }
+                )*
+                $(
+                    if $method == <$c_action as Action>::METHOD {
+                        let id = msg.id.clone().unwrap_or(Id::Null);
+                        let request = match msg.parse_as_request::<$c_action>() {
+                            Ok(request) => request,
+                            Err(e) => {
+                                self.output.failure(id.clone(), e);
+                                self.state.pending.finish(&id);
+                                return Ok(());
+                            }
+                        };
+                        let read_at = msg.read_at;
+                        let token = self.state.pending.register(id.clone());
+                        let action = <$c_action as Action>::new(&mut self.state);
+                        // Snapshot the state now, the messages that follow
+                        // may change it while the request is handled.
+                        let mut ctx = self.ctx.snapshot();
+                        let out = self.output.clone();
+                        let pending = self.state.pending.clone();
+                        let stats = self.state.stats.clone();
+                        let msg = format!("{:?}", msg);
+                        self.workers.execute(move || {
+                            if let Err(e) = request.dispatch_to(action, token, &mut ctx, out) {
+                                debug!("Error handling request {}: {:?}", msg, e);
+                            }
+                            pending.finish(&id);
+                            // Waiting for a worker included.
+                            stats.record(<$c_action as Action>::METHOD, read_at.elapsed());
+                        });
                         handled = true;
                     }
                 )*
                 if !handled {
-                    debug!("Method not found: {}", $method);
+                    match msg.id {
+                        Some(ref id) if !self.ctx.is_inited() => {
+                            debug!("Refusing `{}` before initialization", $method);
+                            let error = ResponseError::ServerNotInitialized.to_rpc_error();
+                            self.output.failure(id.clone(), error);
+                            self.state.pending.finish(id);
+                        }
+                        Some(ref id) => {
+                            debug!("Method not found: {}", $method);
+                            self.output.failure(id.clone(), jsonrpc::Error::method_not_found());
+                            self.state.pending.finish(id);
+                        }
+                        // Protocol specific notifications may be ignored.
+                        None if $method.starts_with("$/") => {
+                            trace!("Ignoring `{}`", $method);
+                        }
+                        None => debug!("Notification not found: {}", $method),
+                    }
                 }
             }
         }
@@ -362,22 +692,48 @@ impl<O: Output> LsService<O> {
                 notifications::Initialized,
                 notifications::DidOpen,
                 notifications::DidChange,
+                notifications::DidClose,
                 notifications::Cancel,
+                notifications::SetTrace,
                 notifications::DidSave,
+                notifications::DidChangeConfiguration,
+                notifications::DidChangeWorkspaceFolders,
                 notifications::DidChangeWatchedFiles;
             requests:
                 ShutdownRequest,
                 InitializeRequest,
+                requests::ServerStatusRequest;
+            concurrent_requests:
                 requests::Completion,
-                requests::ResolveCompletion;
+                requests::ResolveCompletion,
+                requests::OnTypeFormatting,
+                requests::SyntaxTree;
         );
 
         Ok(())
     }
 
+    /// Waits for the next message, giving up on the requests to the client
+    /// that aren't answered in time meanwhile. Returns `None` at the end of
+    /// input, or once the parent process is gone.
+    fn next_message(&mut self) -> Option<Incoming> {
+        loop {
+            if self.state.parent.is_gone() {
+                return None;
+            }
+            self.ctx.expire_client_requests();
+            match self.messages.recv_timeout(Duration::from_secs(EXPIRE_INTERVAL_SECS)) {
+                Ok(msg) => return msg,
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+
     pub fn handle_message(&mut self) -> ServerStateChange {
-        let msg_string = match self.msg_reader.read_message() {
+        let Incoming { text: msg_string, read_at } = match self.next_message() {
             Some(m) => m,
+            None if self.state.parent.is_gone() => return ServerStateChange::Break,
             None => {
                 debug!("Can't read message");
                 self.output.failure(Id::Null, jsonrpc::Error::parse_error());
@@ -387,66 +743,108 @@ impl<O: Output> LsService<O> {
 
         trace!("Read message `{}`", msg_string);
 
-        {
-            let shut_down = self.state.shut_down.load(Ordering::SeqCst);
-            if shut_down {
-                if msg_string != ExitNotification::METHOD {
-                    // We're shutdown, ignore any messages other than 'exit'. This is not actually
-                    // in the spec, I'm not sure we should do this, but it kinda makes sense.
-                    return ServerStateChange::Continue;
-                }
-            }
-        }
-
-        let raw_message = match self.parse_message(&msg_string) {
+        let raw_message = match self.parse_message(&msg_string, read_at) {
             Ok(Some(rm)) => rm,
             Ok(None) => return ServerStateChange::Continue,
+            // Answer and carry on with the next message, the client may
+            // still send valid ones.
             Err(e) => {
                 debug!("parsing error, {:?}", e);
-                self.output.failure(Id::Null, jsonrpc::Error::parse_error());
-                return ServerStateChange::Break;
+                self.output.failure(Id::Null, e);
+                self.state.pending.untrack(&msg_string);
+                return ServerStateChange::Continue;
             }
         };
 
         trace!("Parsed message `{:?}`", raw_message);
 
+        // Only `exit` is expected after `shutdown`, other requests are
+        // refused and other notifications dropped.
+        if self.state.shut_down.load(Ordering::SeqCst)
+            && raw_message.method != ExitNotification::METHOD
+        {
+            debug!("Ignoring `{}` after shutdown", raw_message.method);
+            if let Some(ref id) = raw_message.id {
+                self.output.failure(id.clone(), jsonrpc::Error::invalid_request());
+                self.state.pending.finish(id);
+            }
+            return ServerStateChange::Continue;
+        }
+
+        // Requests are always answered, only notifications fail here and
+        // those are never answered.
         if let Err(e) = self.dispatch_message(&raw_message) {
             debug!("dispatch error, {:?}", e);
-            self.output.failure(raw_message.id.unwrap_or(Id::Null), e);
-            return ServerStateChange::Break;
         }
 
+        // `initialize` is answered by now.
+        if let Some(trace) = self.state.trace.take() {
+            logger::set_trace(trace);
+        }
+
+        if self.state.exited {
+            return ServerStateChange::Break;
+        }
         ServerStateChange::Continue
     }
 }
 
+/// Reads messages on a separate thread, ahead of the ones being handled, so
+/// that `$/cancelRequest` reaches the requests still waiting in the queue.
+fn read_ahead(
+    reader: Box<MessageReader + Send + Sync>,
+    pending: PendingRequests,
+) -> mpsc::Receiver<Option<Incoming>> {
+    let (sender, receiver) = mpsc::channel();
+
+    thread::Builder::new()
+        .name("reader".to_owned())
+        .spawn(move || loop {
+            let msg = reader.read_message().map(|text| {
+                pending.track(&text);
+                Incoming {
+                    text,
+                    read_at: Instant::now(),
+                }
+            });
+
+            // Stop at the end of input or once the service is gone.
+            let done = msg.is_none();
+            if sender.send(msg).is_err() || done {
+                break;
+            }
+        })
+        .expect("Couldn't spawn the reader thread");
+
+    receiver
+}
+
+/// A message as read, with the time it was read at.
+struct Incoming {
+    text: String,
+    read_at: Instant,
+}
+
 #[derive(Debug)]
 struct RawMessage {
     method: String,
     id: Option<Id>,
     params: json::Value,
+    /// Latencies of requests are measured from there.
+    read_at: Instant,
 }
 
 impl RawMessage {
-    fn parse_as_request<'a, T: RequestAction<'a>>(&'a self) -> Result<Request<T>, jsonrpc::Error> {
-
-        // FIXME: We only support numeric responses, ideally we should switch from using parsed usize
-        // to using jsonrpc_core::Id
-        let parsed_numeric_id = match &self.id {
-            &Some(Id::Num(n)) => Some(n as usize),
-            &Some(Id::Str(ref s)) => usize::from_str_radix(s, 10).ok(),
-            _ => None,
-        };
-
+    fn parse_as_request<'a, T: RequestAction<'a>>(&self) -> Result<Request<'a, T>, jsonrpc::Error> {
         let params = T::Params::deserialize(&self.params).map_err(|e| {
             debug!("error when parsing as request: {}", e);
-            jsonrpc::Error::invalid_request()
+            jsonrpc::Error::invalid_params(e.to_string())
         })?;
 
-        match parsed_numeric_id {
-            Some(id) => {
+        match self.id {
+            Some(ref id) => {
                 Ok(Request {
-                    id,
+                    id: id.clone(),
                     params,
                     _action: PhantomData,
                 })
@@ -0,0 +1,83 @@
+// Copyright 2017 Mike Lubinets <lubinetsm@yandex.ru>.
+// See the COPYRIGHT file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Keeps a panicking handler from taking the whole server down.
+
+use backtrace::Backtrace;
+
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+
+/// Logs panics with their backtrace, which reaches stderr, the log file and
+/// the client like any other error.
+pub fn install_hook() {
+    panic::set_hook(Box::new(|info| {
+        let location = info.location()
+            .map(|location| format!("{}:{}", location.file(), location.line()))
+            .unwrap_or_else(|| "unknown location".to_owned());
+        error!(
+            "Thread '{}' panicked at {}: {}\n{:?}",
+            thread::current().name().unwrap_or("<unnamed>"),
+            location,
+            message(info.payload()),
+            Backtrace::new()
+        );
+    }));
+}
+
+/// Runs `f`, turning a panic into an error with the panic message.
+pub fn catch_panic<F: FnOnce() -> R, R>(f: F) -> Result<R, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| message(&*payload))
+}
+
+/// Locks `mutex` even if a panic caught by `catch_panic` poisoned it, for
+/// the state shared by the handlers, which must keep serving later requests.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn message(payload: &(Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_catch_panic() {
+        assert_eq!(catch_panic(|| 42), Ok(42));
+        assert_eq!(catch_panic(|| -> () { panic!("static") }), Err("static".to_owned()));
+        assert_eq!(
+            catch_panic(|| -> () { panic!("formatted {}", 42) }),
+            Err("formatted 42".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_lock_poisoned() {
+        let mutex = Mutex::new(1);
+        let _ = catch_panic(|| {
+            let mut value = mutex.lock().unwrap();
+            *value = 2;
+            panic!("poison");
+        });
+        assert!(mutex.is_poisoned());
+        assert_eq!(*lock(&mutex), 2);
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright 2017 Mike Lubinets <lubinetsm@yandex.ru>.
+// See the COPYRIGHT file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Watches the editor process that started the server, so that the server
+//! doesn't outlive it when it crashes.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// How often the parent process is checked.
+const POLL_INTERVAL_SECS: u64 = 3;
+
+#[derive(Debug, Clone, Default)]
+pub struct ParentWatch {
+    gone: Arc<AtomicBool>,
+}
+
+impl ParentWatch {
+    pub fn new() -> ParentWatch {
+        ParentWatch::default()
+    }
+
+    /// Whether the watched process has exited.
+    pub fn is_gone(&self) -> bool {
+        self.gone.load(Ordering::SeqCst)
+    }
+
+    /// Checks on process `pid` from a separate thread until it exits.
+    pub fn watch(&self, pid: u64) {
+        if is_alive(pid).is_none() {
+            debug!("Can't watch the parent process on this platform");
+            return;
+        }
+
+        let gone = self.gone.clone();
+        let result = thread::Builder::new()
+            .name("parent-watch".to_owned())
+            .spawn(move || loop {
+                if is_alive(pid) == Some(false) {
+                    info!("Parent process {} is gone, exiting", pid);
+                    gone.store(true, Ordering::SeqCst);
+                    break;
+                }
+                thread::sleep(Duration::from_secs(POLL_INTERVAL_SECS));
+            });
+        if let Err(e) = result {
+            debug!("Couldn't spawn the parent watch thread: {}", e);
+        }
+    }
+}
+
+/// Whether process `pid` runs, `None` if there is no way to know.
+#[cfg(target_os = "linux")]
+fn is_alive(pid: u64) -> Option<bool> {
+    use std::path::Path;
+
+    Some(Path::new("/proc").join(pid.to_string()).exists())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_alive(_pid: u64) -> Option<bool> {
+    None
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_watch() {
+        assert_eq!(is_alive(1), Some(true));
+
+        // Above the largest PID Linux hands out.
+        let watch = ParentWatch::new();
+        watch.watch(1 << 32);
+        for _ in 0..50 {
+            if watch.is_gone() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert!(watch.is_gone());
+    }
+}
@@ -0,0 +1,108 @@
+// Copyright 2017 Mike Lubinets <lubinetsm@yandex.ru>.
+// See the COPYRIGHT file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Fixed set of threads running the requests that don't have to wait for
+//! the messages read before them.
+
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Number of requests that can be handled at the same time.
+const WORKER_COUNT: usize = 4;
+
+trait FnBox {
+    fn call_box(self: Box<Self>);
+}
+
+impl<F: FnOnce()> FnBox for F {
+    fn call_box(self: Box<F>) {
+        (*self)()
+    }
+}
+
+type Job = Box<FnBox + Send + 'static>;
+
+pub struct WorkerPool {
+    jobs: mpsc::Sender<Job>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    pub fn new() -> WorkerPool {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..WORKER_COUNT).map(|i| {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("worker-{}", i))
+                .spawn(move || loop {
+                    // Don't hold the lock while running the job.
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job.call_box(),
+                        // The pool is gone.
+                        Err(_) => break,
+                    }
+                })
+                .expect("Couldn't spawn a worker thread")
+        }).collect();
+
+        WorkerPool { jobs, workers }
+    }
+
+    /// Runs `job` on the first free worker.
+    pub fn execute<F: FnOnce() + Send + 'static>(&self, job: F) {
+        self.jobs.send(Box::new(job)).expect("Worker threads are gone");
+    }
+
+    /// Waits for the jobs sent so far to finish, then stops the workers.
+    pub fn join(self) {
+        let WorkerPool { jobs, workers } = self;
+        drop(jobs);
+        for worker in workers {
+            if worker.join().is_err() {
+                debug!("A worker thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_execute() {
+        let pool = WorkerPool::new();
+        let (sender, receiver) = mpsc::channel();
+        for i in 0..WORKER_COUNT * 2 {
+            let sender = sender.clone();
+            pool.execute(move || sender.send(i).unwrap());
+        }
+
+        let mut done: Vec<usize> = receiver.iter().take(WORKER_COUNT * 2).collect();
+        done.sort();
+        assert_eq!(done, (0..WORKER_COUNT * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_join() {
+        let pool = WorkerPool::new();
+        let (sender, receiver) = mpsc::channel();
+        for i in 0..WORKER_COUNT * 2 {
+            let sender = sender.clone();
+            pool.execute(move || sender.send(i).unwrap());
+        }
+        pool.join();
+
+        assert_eq!(receiver.try_iter().count(), WORKER_COUNT * 2);
+    }
+}
@@ -0,0 +1,466 @@
+// Copyright 2017 Mike Lubinets <lubinetsm@yandex.ru>.
+// See the COPYRIGHT file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Recording of sessions to a trace file, and their replay, to reproduce the
+//! bugs users hit in their editor.
+//!
+//! A trace has one JSON object per line, holding a message as it was read or
+//! written along with its direction and time. A replay only compares the
+//! responses, the notifications and requests sent to the client depend on
+//! the timing of the session, e.g. the debounced diagnostics.
+
+use chrono;
+use json;
+use vfs::Vfs;
+use server::{LsService, MessageReader, Output};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::vec;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum Direction {
+    /// From the client to the server.
+    #[serde(rename = "in")]
+    In,
+    /// From the server to the client.
+    #[serde(rename = "out")]
+    Out,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    time: String,
+    direction: Direction,
+    message: String,
+}
+
+/// Appends the messages of a session to a trace file.
+#[derive(Clone)]
+pub struct Recorder {
+    file: Arc<Mutex<File>>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Recorder> {
+        let file = File::create(path)?;
+        Ok(Recorder { file: Arc::new(Mutex::new(file)) })
+    }
+
+    fn record(&self, direction: Direction, message: &str) {
+        let entry = Entry {
+            time: chrono::Local::now().to_rfc3339(),
+            direction,
+            message: message.to_owned(),
+        };
+        let line = json::to_string(&entry).unwrap();
+
+        // Logged without the lock, the log may be sent to the client, and
+        // so recorded.
+        let result = writeln!(self.file.lock().unwrap(), "{}", line);
+        if let Err(e) = result {
+            debug!("Couldn't record a message: {}", e);
+        }
+    }
+}
+
+/// Records the messages read by `reader`.
+pub struct RecordingReader {
+    reader: Box<MessageReader + Send + Sync>,
+    recorder: Recorder,
+}
+
+impl RecordingReader {
+    pub fn new(reader: Box<MessageReader + Send + Sync>, recorder: Recorder) -> RecordingReader {
+        RecordingReader { reader, recorder }
+    }
+}
+
+impl MessageReader for RecordingReader {
+    fn read_message(&self) -> Option<String> {
+        let message = self.reader.read_message();
+        if let Some(ref message) = message {
+            self.recorder.record(Direction::In, message);
+        }
+        message
+    }
+}
+
+/// Records the messages written to `output`.
+#[derive(Clone)]
+pub struct RecordingOutput<O> {
+    output: O,
+    recorder: Recorder,
+}
+
+impl<O: Output> RecordingOutput<O> {
+    pub fn new(output: O, recorder: Recorder) -> RecordingOutput<O> {
+        RecordingOutput { output, recorder }
+    }
+}
+
+impl<O: Output> Output for RecordingOutput<O> {
+    fn response(&self, output: String) {
+        self.recorder.record(Direction::Out, &output);
+        self.output.response(output);
+    }
+
+    fn provide_id(&self) -> u32 {
+        self.output.provide_id()
+    }
+
+    fn flush(&self) {
+        self.output.flush()
+    }
+}
+
+/// Reads the messages the client sent in a recorded session.
+struct ReplayReader {
+    messages: Mutex<vec::IntoIter<String>>,
+}
+
+impl MessageReader for ReplayReader {
+    fn read_message(&self) -> Option<String> {
+        self.messages.lock().unwrap().next()
+    }
+}
+
+/// Collects the messages written during a replay.
+#[derive(Clone)]
+struct ReplayOutput {
+    messages: Arc<Mutex<Vec<String>>>,
+    // Numbered like the live outputs, so that the requests to the client
+    // get the ids they had in the recorded session.
+    next_id: Arc<AtomicU32>,
+}
+
+impl Output for ReplayOutput {
+    fn response(&self, output: String) {
+        self.messages.lock().unwrap().push(output);
+    }
+
+    fn provide_id(&self) -> u32 {
+        self.next_id.fetch_add(1, Ordering::SeqCst)
+    }
+}
+
+/// Feeds the messages the client sent in the session recorded in `trace` to
+/// a new service, as fast as it takes them, and compares its responses with
+/// the recorded ones.
+pub fn replay(vfs: Arc<Vfs>, trace: &Path) -> io::Result<Report> {
+    let mut incoming = vec![];
+    let mut expected = vec![];
+    for line in BufReader::new(File::open(trace)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: Entry = json::from_str(&line)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        match entry.direction {
+            Direction::In => incoming.push(entry.message),
+            Direction::Out => expected.push(entry.message),
+        }
+    }
+
+    let replayed = incoming.len();
+    let reader = ReplayReader { messages: Mutex::new(incoming.into_iter()) };
+    let output = ReplayOutput {
+        messages: Arc::new(Mutex::new(vec![])),
+        next_id: Arc::new(AtomicU32::new(1)),
+    };
+    LsService::new(vfs, Box::new(reader), output.clone()).run();
+
+    let actual = output.messages.lock().unwrap().clone();
+    Ok(Report {
+        replayed,
+        differences: diff(&expected, &actual),
+    })
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Difference {
+    /// Recorded, but not written in the replay.
+    Missing(json::Value),
+    /// Written in the replay, but not recorded.
+    Unexpected(json::Value),
+    Changed {
+        expected: json::Value,
+        actual: json::Value,
+    },
+}
+
+/// Outcome of a replay.
+#[derive(Debug)]
+pub struct Report {
+    /// Number of messages fed to the service.
+    pub replayed: usize,
+    pub differences: Vec<Difference>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.differences.is_empty()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for difference in &self.differences {
+            match *difference {
+                Difference::Missing(ref message) => writeln!(f, "- {}", message)?,
+                Difference::Unexpected(ref message) => writeln!(f, "+ {}", message)?,
+                Difference::Changed {
+                    ref expected,
+                    ref actual,
+                } => {
+                    writeln!(f, "- {}", expected)?;
+                    writeln!(f, "+ {}", actual)?;
+                }
+            }
+        }
+        write!(
+            f,
+            "Replayed {} messages, {} differences",
+            self.replayed,
+            self.differences.len()
+        )
+    }
+}
+
+/// Pairs the responses by id, since concurrent requests may be answered in
+/// any order. The other messages are left out.
+fn diff(expected: &[String], actual: &[String]) -> Vec<Difference> {
+    let expected = responses(expected);
+    let mut actual_by_id: HashMap<String, json::Value> = responses(actual).into_iter().collect();
+
+    let mut differences = vec![];
+    for (id, expected) in expected {
+        match actual_by_id.remove(&id) {
+            Some(ref actual) if *actual == expected => {}
+            Some(actual) => differences.push(Difference::Changed { expected, actual }),
+            None => differences.push(Difference::Missing(expected)),
+        }
+    }
+
+    // Keep the order they were written in.
+    for (id, actual) in responses(actual) {
+        if actual_by_id.remove(&id).is_some() {
+            differences.push(Difference::Unexpected(actual));
+        }
+    }
+    differences
+}
+
+/// Returns the responses among `messages`, with their ids.
+fn responses(messages: &[String]) -> Vec<(String, json::Value)> {
+    messages
+        .iter()
+        .filter_map(|message| json::from_str::<json::Value>(message).ok())
+        .filter(|message| message.get("method").is_none())
+        .map(|message| {
+            let id = message.get("id").unwrap_or(&json::Value::Null).to_string();
+            (id, message)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    /// A path in the temporary directory that no other test run uses.
+    fn temp_trace(name: &str) -> PathBuf {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        env::temp_dir().join(format!(
+            "akkadia-test-{}-{}-{}.jsonl",
+            name,
+            now.as_secs(),
+            now.subsec_nanos()
+        ))
+    }
+
+    /// Reads the messages after the given number of milliseconds each, like
+    /// a client would.
+    struct PacedReader {
+        messages: Mutex<vec::IntoIter<(u64, String)>>,
+    }
+
+    impl MessageReader for PacedReader {
+        fn read_message(&self) -> Option<String> {
+            let (delay, message) = self.messages.lock().unwrap().next()?;
+            thread::sleep(Duration::from_millis(delay));
+            Some(message)
+        }
+    }
+
+    /// Serves `reader` while recording the session to `trace`, returns the
+    /// messages written.
+    fn record<R: MessageReader + Send + Sync + 'static>(reader: R, trace: &Path) -> Vec<String> {
+        let recorder = Recorder::create(trace).unwrap();
+        let output = ReplayOutput {
+            messages: Arc::new(Mutex::new(vec![])),
+            next_id: Arc::new(AtomicU32::new(1)),
+        };
+        LsService::new(
+            Arc::new(Vfs::new()),
+            Box::new(RecordingReader::new(Box::new(reader), recorder.clone())),
+            RecordingOutput::new(output.clone(), recorder),
+        ).run();
+        let messages = output.messages.lock().unwrap().clone();
+        messages
+    }
+
+    #[test]
+    fn test_diff() {
+        let messages = |messages: &[&str]| -> Vec<String> {
+            messages.iter().map(|message| message.to_string()).collect()
+        };
+        let expected = messages(&[
+            r#"{"id":1,"result":1}"#,
+            r#"{"id":2,"result":2}"#,
+            r#"{"method":"m","params":1}"#,
+            r#"{"method":"m","params":2}"#,
+            r#"{"method":"window/logMessage","params":{}}"#,
+        ]);
+
+        // Responses may come in any order, the other messages don't count.
+        let actual = messages(&[
+            r#"{"id":2,"result":2}"#,
+            r#"{"id":1,"result":1}"#,
+            r#"{"method":"m","params":3}"#,
+        ]);
+        assert_eq!(diff(&expected, &actual), vec![]);
+
+        let actual = messages(&[
+            r#"{"id":1,"result":3}"#,
+            r#"{"method":"m","params":1}"#,
+            r#"{"id":3,"result":3}"#,
+        ]);
+        assert_eq!(
+            diff(&expected, &actual),
+            vec![
+                Difference::Changed {
+                    expected: json!({"id": 1, "result": 1}),
+                    actual: json!({"id": 1, "result": 3}),
+                },
+                Difference::Missing(json!({"id": 2, "result": 2})),
+                Difference::Unexpected(json!({"id": 3, "result": 3})),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let root = env::current_dir().unwrap().join("test_data").join("common");
+        let trace = temp_trace("record-and-replay");
+
+        let initialize = json!({
+            "jsonrpc": "2.0",
+            "id": 0,
+            "method": "initialize",
+            "params": { "rootPath": root, "capabilities": {} },
+        });
+        let messages = vec![
+            initialize.to_string(),
+            r#"{"jsonrpc":"2.0","id":1,"method":"textDocument/hover","params":{}}"#.to_owned(),
+            r#"{"jsonrpc":"2.0","id":2,"method":"shutdown"}"#.to_owned(),
+            r#"{"jsonrpc":"2.0","method":"exit"}"#.to_owned(),
+        ];
+
+        let reader = ReplayReader { messages: Mutex::new(messages.into_iter()) };
+        assert_eq!(record(reader, &trace).len(), 3);
+
+        let report = replay(Arc::new(Vfs::new()), &trace).unwrap();
+        fs::remove_file(&trace).unwrap();
+        assert_eq!(report.replayed, 4);
+        assert!(report.is_clean(), "{}", report);
+    }
+
+    #[test]
+    fn test_replay_edits() {
+        let root = env::current_dir().unwrap().join("test_data").join("common");
+        let uri = format!("file://{}", root.join("src").join("main.slang").display());
+        let trace = temp_trace("replay-edits");
+
+        let messages = vec![
+            json!({
+                "jsonrpc": "2.0",
+                "id": 0,
+                "method": "initialize",
+                "params": { "rootPath": root, "capabilities": {} },
+            }),
+            json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} }),
+            json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didOpen",
+                "params": {
+                    "textDocument": {
+                        "uri": uri,
+                        "languageId": "slang",
+                        "version": 1,
+                        "text": "rand: Integer is\n",
+                    },
+                },
+            }),
+            json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didChange",
+                "params": {
+                    "textDocument": { "uri": uri, "version": 2 },
+                    "contentChanges": [{ "text": "rand: Integer is\nend\nend\n" }],
+                },
+            }),
+            json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": "akkadia/syntaxTree",
+                "params": { "textDocument": { "uri": uri } },
+            }),
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "shutdown" }),
+            json!({ "jsonrpc": "2.0", "method": "exit" }),
+        ];
+        // Leave time for the diagnostics of the edit before shutting down.
+        let delays = vec![0, 0, 0, 0, 500, 0, 0];
+        let reader = PacedReader {
+            messages: Mutex::new(
+                delays
+                    .into_iter()
+                    .zip(messages.iter().map(|message| message.to_string()))
+                    .collect::<Vec<_>>()
+                    .into_iter(),
+            ),
+        };
+        let recorded = record(reader, &trace);
+        assert!(
+            recorded
+                .iter()
+                .any(|message| message.contains("publishDiagnostics")
+                    && message.contains("unexpected `end`"))
+        );
+
+        // Replayed at once, the session ends before the diagnostics are sent.
+        let report = replay(Arc::new(Vfs::new()), &trace).unwrap();
+        fs::remove_file(&trace).unwrap();
+        assert_eq!(report.replayed, 7);
+        assert!(report.is_clean(), "{}", report);
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright 2017 Mike Lubinets <lubinetsm@yandex.ru>.
+// See the COPYRIGHT file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Statistics about the requests handled, reported by `akkadia/serverStatus`.
+
+use lsp_data::MethodStatus;
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Latencies are computed over the last requests of every method.
+const MAX_SAMPLES: usize = 1000;
+
+#[derive(Debug, Default)]
+struct MethodStats {
+    count: u64,
+    /// Latencies of the last requests, in milliseconds.
+    samples: VecDeque<f64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    methods: Arc<Mutex<HashMap<String, MethodStats>>>,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats::default()
+    }
+
+    /// Counts a request to `method`, answered `latency` after it was read.
+    pub fn record(&self, method: &str, latency: Duration) {
+        let mut methods = self.methods.lock().unwrap();
+        let stats = methods.entry(method.to_owned()).or_insert_with(MethodStats::default);
+        stats.count += 1;
+        if stats.samples.len() == MAX_SAMPLES {
+            stats.samples.pop_front();
+        }
+        stats.samples.push_back(millis(latency));
+    }
+
+    pub fn methods(&self) -> BTreeMap<String, MethodStatus> {
+        let methods = self.methods.lock().unwrap();
+        methods
+            .iter()
+            .map(|(method, stats)| {
+                let mut samples: Vec<f64> = stats.samples.iter().cloned().collect();
+                samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                let status = MethodStatus {
+                    count: stats.count,
+                    p50_ms: percentile(&samples, 50),
+                    p90_ms: percentile(&samples, 90),
+                    p99_ms: percentile(&samples, 99),
+                    max_ms: samples.last().cloned().unwrap_or(0.0),
+                };
+                (method.clone(), status)
+            })
+            .collect()
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1_000_000.0
+}
+
+/// Nearest-rank percentile of sorted `samples`.
+fn percentile(samples: &[f64], percent: usize) -> f64 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    let rank = (samples.len() * percent + 99) / 100;
+    samples[cmp::max(rank, 1) - 1]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_methods() {
+        let stats = Stats::new();
+        for ms in 1..101 {
+            stats.record("textDocument/completion", Duration::from_millis(ms));
+        }
+        stats.record("initialize", Duration::from_millis(5));
+
+        let methods = stats.methods();
+        assert_eq!(
+            methods["textDocument/completion"],
+            MethodStatus {
+                count: 100,
+                p50_ms: 50.0,
+                p90_ms: 90.0,
+                p99_ms: 99.0,
+                max_ms: 100.0,
+            }
+        );
+        assert_eq!(methods["initialize"].count, 1);
+        assert_eq!(methods["initialize"].p99_ms, 5.0);
+
+        for _ in 0..MAX_SAMPLES {
+            stats.record("initialize", Duration::from_millis(1));
+        }
+        let methods = stats.methods();
+        assert_eq!(methods["initialize"].count, MAX_SAMPLES as u64 + 1);
+        assert_eq!(methods["initialize"].max_ms, 1.0);
+    }
+}
@@ -0,0 +1,95 @@
+// Copyright 2017 Mike Lubinets <lubinetsm@yandex.ru>.
+// See the COPYRIGHT file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Channels the client can talk to the server through.
+
+use std::fmt;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+const UNIX_PREFIX: &'static str = "unix:";
+const TCP_PREFIX: &'static str = "tcp:";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transport {
+    /// Standard input and output of the process.
+    Stdio,
+    /// The first connection accepted on a TCP address, host names are
+    /// resolved when parsed.
+    Tcp(SocketAddr),
+    /// The first connection accepted on a Unix domain socket.
+    Unix(PathBuf),
+}
+
+impl FromStr for Transport {
+    type Err = String;
+
+    /// Parses a `--listen` address, either `[tcp:]host:port` or `unix:path`.
+    fn from_str(addr: &str) -> Result<Transport, String> {
+        if addr.starts_with(UNIX_PREFIX) {
+            let path = &addr[UNIX_PREFIX.len()..];
+            if path.is_empty() {
+                return Err("missing socket path".to_owned());
+            }
+            return Ok(Transport::Unix(PathBuf::from(path)));
+        }
+
+        let addr = if addr.starts_with(TCP_PREFIX) {
+            &addr[TCP_PREFIX.len()..]
+        } else {
+            addr
+        };
+        let mut addrs = addr.to_socket_addrs()
+            .map_err(|e| format!("invalid address `{}`: {}", addr, e))?;
+        addrs
+            .next()
+            .map(Transport::Tcp)
+            .ok_or_else(|| format!("`{}` doesn't resolve to any address", addr))
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Transport::Stdio => write!(f, "stdio"),
+            Transport::Tcp(ref addr) => write!(f, "{}{}", TCP_PREFIX, addr),
+            Transport::Unix(ref path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_transport() {
+        let tcp = Transport::Tcp("127.0.0.1:9257".parse().unwrap());
+        assert_eq!("127.0.0.1:9257".parse(), Ok(tcp.clone()));
+        assert_eq!("tcp:127.0.0.1:9257".parse(), Ok(tcp.clone()));
+        assert_eq!(tcp.to_string(), "tcp:127.0.0.1:9257");
+
+        let unix = Transport::Unix(PathBuf::from("/tmp/akkadia.sock"));
+        assert_eq!("unix:/tmp/akkadia.sock".parse(), Ok(unix.clone()));
+        assert_eq!(unix.to_string(), "unix:/tmp/akkadia.sock");
+
+        assert!("unix:".parse::<Transport>().is_err());
+        assert!("localhost".parse::<Transport>().is_err());
+
+        match "tcp:localhost:9257".parse() {
+            Ok(Transport::Tcp(addr)) => {
+                assert!(addr.ip().is_loopback());
+                assert_eq!(addr.port(), 9257);
+            }
+            other => panic!("unexpected transport {:?}", other),
+        }
+    }
+}
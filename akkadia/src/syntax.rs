@@ -0,0 +1,258 @@
+// Copyright 2017 Mike Lubinets <lubinetsm@yandex.ru>.
+// See the COPYRIGHT file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Line-level recognition of the Slang block structure.
+//!
+//! A block is opened by a `name: Type is` header and closed by `end name`.
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Line<'a> {
+    /// Block header, holds the name of the opened block.
+    Header(&'a str),
+    /// Block end, holds the name following `end` if any.
+    End(Option<&'a str>),
+    Other,
+}
+
+pub fn classify(line: &str) -> Line {
+    let line = line.trim();
+
+    if line == "end" {
+        return Line::End(None);
+    }
+    if line.starts_with("end ") {
+        let name = line["end ".len()..].trim();
+        return Line::End(if name.is_empty() { None } else { Some(name) });
+    }
+
+    if line == "is" || !line.ends_with(" is") {
+        return Line::Other;
+    }
+
+    let name_len = line.find(|c: char| !is_ident_char(c)).unwrap_or(line.len());
+    let (name, rest) = line.split_at(name_len);
+    let rest = rest.trim_left();
+
+    if name.is_empty() || !(rest.starts_with(':') || rest.starts_with('(')) {
+        return Line::Other;
+    }
+
+    Line::Header(name)
+}
+
+/// Kind of a node of the syntax tree.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum NodeKind {
+    SourceFile,
+    Block,
+    Header,
+    End,
+    /// Any other line of a block or of the file.
+    Line,
+    /// An `end` without a block to close.
+    Error,
+}
+
+impl NodeKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            NodeKind::SourceFile => "SOURCE_FILE",
+            NodeKind::Block => "BLOCK",
+            NodeKind::Header => "HEADER",
+            NodeKind::End => "END",
+            NodeKind::Line => "LINE",
+            NodeKind::Error => "ERROR",
+        }
+    }
+}
+
+/// (row, column) in a text, columns count characters.
+pub type Point = (usize, usize);
+
+/// Node of the syntax tree, lines are its leaves. Blank lines and the
+/// whitespace around lines are left out.
+#[derive(Debug, PartialEq)]
+pub struct Node<'a> {
+    pub kind: NodeKind,
+    pub start: Point,
+    pub end: Point,
+    /// Text of the line, empty for the other nodes.
+    pub text: &'a str,
+    pub children: Vec<Node<'a>>,
+}
+
+impl<'a> Node<'a> {
+    /// Returns the deepest node spanning from `start` to `end`.
+    pub fn covering(&self, start: Point, end: Point) -> &Node<'a> {
+        self.children
+            .iter()
+            .find(|child| child.start <= start && end <= child.end)
+            .map_or(self, |child| child.covering(start, end))
+    }
+
+    /// Pretty-prints the tree, one node per line, as `KIND@start..end "text"`.
+    pub fn dump(&self) -> String {
+        fn dump_node(node: &Node, depth: usize, out: &mut String) {
+            out.push_str(&format!(
+                "{:indent$}{}@{}:{}..{}:{}",
+                "",
+                node.kind.name(),
+                node.start.0,
+                node.start.1,
+                node.end.0,
+                node.end.1,
+                indent = depth * 2
+            ));
+            if !node.text.is_empty() {
+                out.push_str(&format!(" {:?}", node.text));
+            }
+            out.push('\n');
+            for child in &node.children {
+                dump_node(child, depth + 1, out);
+            }
+        }
+
+        let mut out = String::new();
+        dump_node(self, 0, &mut out);
+        out
+    }
+}
+
+/// Returns the syntax tree of `text`.
+pub fn syntax_tree(text: &str) -> Node {
+    fn close<'a>(mut block: Node<'a>, open: &mut Vec<Node<'a>>, root: &mut Node<'a>) {
+        // Up to the `end` line, or the last line of the block if unclosed.
+        if let Some(last) = block.children.last() {
+            block.end = last.end;
+        }
+        push(block, open, root);
+    }
+
+    fn push<'a>(node: Node<'a>, open: &mut Vec<Node<'a>>, root: &mut Node<'a>) {
+        match open.last_mut() {
+            Some(parent) => parent.children.push(node),
+            None => root.children.push(node),
+        }
+    }
+
+    let rows = text.lines().count();
+    let last_line = text.lines().last().unwrap_or("");
+    let end = if text.ends_with('\n') || text.is_empty() {
+        (rows, 0)
+    } else {
+        (rows - 1, last_line.chars().count())
+    };
+    let mut root = Node {
+        kind: NodeKind::SourceFile,
+        start: (0, 0),
+        end,
+        text: "",
+        children: vec![],
+    };
+    let mut open: Vec<Node> = vec![];
+
+    for (row, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let leaf = |kind| Node {
+            kind,
+            start: (row, indentation(line).chars().count()),
+            end: (row, line.trim_right().chars().count()),
+            text: line.trim(),
+            children: vec![],
+        };
+
+        match classify(line) {
+            Line::Header(_) => {
+                let header = leaf(NodeKind::Header);
+                open.push(Node {
+                    kind: NodeKind::Block,
+                    start: header.start,
+                    end: header.end,
+                    text: "",
+                    children: vec![header],
+                });
+            }
+            Line::End(_) => match open.pop() {
+                Some(mut block) => {
+                    block.children.push(leaf(NodeKind::End));
+                    close(block, &mut open, &mut root);
+                }
+                None => push(leaf(NodeKind::Error), &mut open, &mut root),
+            },
+            Line::Other => push(leaf(NodeKind::Line), &mut open, &mut root),
+        }
+    }
+
+    while let Some(block) = open.pop() {
+        close(block, &mut open, &mut root);
+    }
+
+    root
+}
+
+/// Returns the leading whitespace of `line`.
+pub fn indentation(line: &str) -> &str {
+    let len = line.len() - line.trim_left().len();
+    &line[..len]
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify("rand: Integer is"), Line::Header("rand"));
+        assert_eq!(classify("  rand(x: Integer): Integer is  "), Line::Header("rand"));
+        assert_eq!(classify("rand : Integer is"), Line::Header("rand"));
+        assert_eq!(classify("this is"), Line::Other);
+        assert_eq!(classify("is"), Line::Other);
+        assert_eq!(classify(": Integer is"), Line::Other);
+        assert_eq!(classify("rand: Integer isnt"), Line::Other);
+
+        assert_eq!(classify("end"), Line::End(None));
+        assert_eq!(classify("  end rand"), Line::End(Some("rand")));
+        assert_eq!(classify("endless"), Line::Other);
+        assert_eq!(classify(" return 4"), Line::Other);
+    }
+
+    #[test]
+    fn test_syntax_tree() {
+        let text = "outer: Integer is\n  inner: Integer is\n    return 4\n  end inner\n\nend outer\nend\nopen: Integer is\n  x";
+        let tree = syntax_tree(text);
+        assert_eq!(
+            tree.dump(),
+            "SOURCE_FILE@0:0..8:3
+  BLOCK@0:0..5:9
+    HEADER@0:0..0:17 \"outer: Integer is\"
+    BLOCK@1:2..3:11
+      HEADER@1:2..1:19 \"inner: Integer is\"
+      LINE@2:4..2:12 \"return 4\"
+      END@3:2..3:11 \"end inner\"
+    END@5:0..5:9 \"end outer\"
+  ERROR@6:0..6:3 \"end\"
+  BLOCK@7:0..8:3
+    HEADER@7:0..7:16 \"open: Integer is\"
+    LINE@8:2..8:3 \"x\"
+"
+        );
+
+        assert_eq!(tree.covering((2, 5), (2, 7)).kind, NodeKind::Line);
+        assert_eq!(tree.covering((1, 2), (3, 0)).kind, NodeKind::Block);
+        assert_eq!(tree.covering((1, 0), (1, 5)).kind, NodeKind::Block);
+        assert_eq!(tree.covering((4, 0), (7, 0)).kind, NodeKind::SourceFile);
+    }
+}
@@ -13,33 +13,59 @@ use std::env;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use std::thread;
 use std::time::{Duration, SystemTime};
 
+use log::LogLevelFilter;
 use lstypes;
 use json;
 use server as ls_server;
 use vfs;
 
-use init_logger;
+use cli::Options;
+use logger::{self, Trace};
 
 const TEST_TIMEOUT_IN_SEC: u64 = 320;
 
+lazy_static! {
+    /// The logger is global, the tests forwarding logs to the client run alone.
+    static ref LOGGER: RwLock<()> = RwLock::new(());
+}
+
+enum LoggerGuard {
+    Shared(RwLockReadGuard<'static, ()>),
+    Exclusive(RwLockWriteGuard<'static, ()>),
+}
+
 pub struct Environment {
     pub cache: Cache,
     pub target_path: PathBuf,
+    _logger: LoggerGuard,
 }
 
 impl Environment {
     pub fn new(project_dir: &str) -> Self {
+        // Tests failing while holding the lock don't concern the others.
+        let guard = LOGGER.read().unwrap_or_else(|e| e.into_inner());
+        Environment::with_logger(project_dir, LoggerGuard::Shared(guard))
+    }
+
+    /// For the tests forwarding logs to the client, see `forward_logs`.
+    pub fn exclusive(project_dir: &str) -> Self {
+        let guard = LOGGER.write().unwrap_or_else(|e| e.into_inner());
+        Environment::with_logger(project_dir, LoggerGuard::Exclusive(guard))
+    }
+
+    fn with_logger(project_dir: &str, logger: LoggerGuard) -> Self {
         use std::sync::atomic::{AtomicUsize, Ordering};
 
         lazy_static! {
             static ref COUNTER: AtomicUsize = AtomicUsize::new(0);
         }
 
-        init_logger();
+        let options = Options::default();
+        let _ = logger::init(&options.log_file, options.log_level);
 
         let cur_dir = env::current_dir().expect("Could not find current working directory");
         let project_path = cur_dir.join("test_data").join(project_dir);
@@ -53,7 +79,11 @@ impl Environment {
 
         let cache = Cache::new(project_path);
 
-        Self { cache, target_path }
+        Self {
+            cache,
+            target_path,
+            _logger: logger,
+        }
     }
 }
 
@@ -149,6 +179,23 @@ impl ls_server::Output for RecordOutput {
     }
 }
 
+/// Forwards the logs to the client with the other messages in `results`, as
+/// `serve_client` does, until dropped. Only for `Environment::exclusive` tests.
+pub struct ForwardLogs;
+
+pub fn forward_logs(results: LsResultList) -> ForwardLogs {
+    logger::forward_to(RecordOutput { output: results });
+    ForwardLogs
+}
+
+impl Drop for ForwardLogs {
+    fn drop(&mut self) {
+        logger::stop_forwarding();
+        logger::set_client_level(LogLevelFilter::Info);
+        logger::set_trace(Trace::Off);
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct ExpectedMessage {
     id: Option<u64>,
@@ -221,6 +268,27 @@ pub fn expect_messages(results: LsResultList, expected: &[&ExpectedMessage]) {
     *results = vec![];
 }
 
+/// Waits for `count` messages and returns them ordered by id, for responses
+/// to requests handled concurrently.
+pub fn take_messages_by_id(results: LsResultList, count: usize) -> Vec<json::Value> {
+    let start_clock = SystemTime::now();
+    while results.lock().unwrap().len() < count {
+        if start_clock.elapsed().unwrap().as_secs() >= TEST_TIMEOUT_IN_SEC {
+            panic!("Hit timeout");
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    let mut results = results.lock().unwrap();
+    assert_eq!(results.len(), count);
+    let mut values: Vec<json::Value> = results
+        .drain(..)
+        .map(|result| json::from_str(&result).unwrap())
+        .collect();
+    values.sort_by_key(|value| value.get("id").and_then(|id| id.as_u64()));
+    values
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct Src<'a, 'b> {
     pub file_name: &'a Path,
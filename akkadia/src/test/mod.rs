@@ -14,25 +14,31 @@
 mod harness;
 
 use actions::requests;
-use server::{self as ls_server, Request};
+use actions::notifications;
+use server::{self as ls_server, Notification, Request};
 use jsonrpc;
 use vfs;
 
-use self::harness::{Environment, expect_messages, ExpectedMessage, RecordOutput, src};
+use self::harness::{Environment, expect_messages, forward_logs, take_messages_by_id,
+                    ExpectedMessage, RecordOutput, src};
 
 use lstypes::*;
-use lsp_data::InitializationOptions;
+use lsp_data::{InitializationOptions, InitializeParams, WorkspaceFolder,
+               DidChangeWorkspaceFoldersParams, WorkspaceFoldersChangeEvent, SyntaxTreeParams,
+               SetTraceParams};
 
 use json;
 use std::marker::PhantomData;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 use url::Url;
 
 pub fn initialize<'a>(
     id: usize,
     root_path: Option<String>,
-) -> Request<'a, ls_server::InitializeRequest> {
+) -> Request<'a, ls_server::InitializeRequest<'a>> {
     initialize_with_opts(id, root_path, None)
 }
 
@@ -40,22 +46,23 @@ pub fn initialize_with_opts<'a>(
     id: usize,
     root_path: Option<String>,
     initialization_options: Option<InitializationOptions>,
-) -> Request<'a, ls_server::InitializeRequest> {
+) -> Request<'a, ls_server::InitializeRequest<'a>> {
     let init_opts = initialization_options.map(|val| json::to_value(val).unwrap());
     let params = InitializeParams {
         process_id: None,
         root_path,
         root_uri: None,
         initialization_options: init_opts,
-        capabilities: ClientCapabilities {
+        capabilities: json::to_value(ClientCapabilities {
             workspace: None,
             text_document: None,
             experimental: None,
-        },
+        }).unwrap(),
         trace: TraceOption::Off,
+        workspace_folders: None,
     };
     Request {
-        id,
+        id: jsonrpc::Id::Num(id as u64),
         params,
         _action: PhantomData,
     }
@@ -66,7 +73,16 @@ pub fn request<'a, T: ls_server::RequestAction<'a>>(
     params: T::Params,
 ) -> Request<'a, T> {
     Request {
-        id,
+        id: jsonrpc::Id::Num(id as u64),
+        params,
+        _action: PhantomData,
+    }
+}
+
+pub fn notification<'a, T: ls_server::NotificationAction<'a>>(
+    params: T::Params,
+) -> Notification<'a, T> {
+    Notification {
         params,
         _action: PhantomData,
     }
@@ -116,8 +132,1209 @@ fn test_completion() {
         results.clone(),
         &[
             ExpectedMessage::new(Some(11)).expect_contains(
-                r#"[{"label":"completion","detail":"test completion"}]"#,
+                r#"[{"label":"completion","detail":"test completion","documentation":"test completion"}]"#,
+            ),
+        ],
+    );
+}
+
+/// Returns the documentation of the snippet completion item, as shown to a
+/// client with `capabilities`.
+fn completion_documentation(capabilities: json::Value) -> json::Value {
+    let mut env = Environment::new("common");
+
+    let source_file_path = Path::new("src").join("main.slang");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let url = Url::from_file_path(env.cache.abs_path(&source_file_path))
+        .expect("couldn't convert file path to URL");
+
+    let mut init = initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned()));
+    init.params.capabilities = capabilities;
+    let messages = vec![
+        init.to_string(),
+        request::<requests::Completion>(
+            1,
+            TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier::new(url),
+                position: env.cache.mk_ls_position(src(&source_file_path, 1, "Int")),
+            },
+        ).to_string(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    for _ in 0..2 {
+        assert_eq!(
+            ls_server::LsService::handle_message(&mut server),
+            ls_server::ServerStateChange::Continue
+        );
+    }
+
+    let responses = take_messages_by_id(results, 2);
+    let items = responses[1]["result"].as_array().unwrap();
+    let item = items.iter().find(|item| item["label"] == "completion").unwrap();
+    item["documentation"].clone()
+}
+
+#[test]
+fn test_completion_documentation_markdown() {
+    let documentation = completion_documentation(json!({
+        "textDocument": {
+            "completion": {
+                "completionItem": { "documentationFormat": ["markdown", "plaintext"] },
+            },
+        },
+    }));
+    assert_eq!(documentation, json!({ "kind": "markdown", "value": "*test completion*" }));
+}
+
+#[test]
+fn test_completion_documentation_plaintext() {
+    let documentation = completion_documentation(json!({
+        "textDocument": {
+            "completion": {
+                "completionItem": { "documentationFormat": ["plaintext"] },
+            },
+        },
+    }));
+    assert_eq!(documentation, json!("test completion"));
+}
+
+#[test]
+fn test_did_close_clears_diagnostics() {
+    let mut env = Environment::new("common");
+
+    let source_file_path = Path::new("src").join("main.slang");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let url = Url::from_file_path(env.cache.abs_path(&source_file_path))
+        .expect("couldn't convert file path to URL");
+
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned()))
+            .to_string(),
+        notification::<notifications::DidOpen>(DidOpenTextDocumentParams {
+            text_document: TextDocumentItem::new(
+                url.clone(),
+                Some("slang".to_owned()),
+                Some(1),
+                "unsaved: Integer is\nend unsaved\n".to_owned(),
+            ),
+        }).to_string(),
+        notification::<notifications::DidClose>(DidCloseTextDocumentParams {
+            text_document: TextDocumentIdentifier::new(url.clone()),
+        }).to_string(),
+        request::<requests::SyntaxTree>(
+            1,
+            SyntaxTreeParams {
+                text_document: TextDocumentIdentifier::new(url.clone()),
+                range: None,
+            },
+        ).to_string(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    assert_eq!(
+        ls_server::LsService::handle_message(&mut server),
+        ls_server::ServerStateChange::Continue
+    );
+    expect_messages(
+        results.clone(),
+        &[
+            ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+        ],
+    );
+
+    for _ in 0..3 {
+        assert_eq!(
+            ls_server::LsService::handle_message(&mut server),
+            ls_server::ServerStateChange::Continue
+        );
+    }
+    // The unsaved edits are gone, the file is read from disk again.
+    expect_messages(
+        results.clone(),
+        &[
+            ExpectedMessage::new(None)
+                .expect_contains("textDocument/publishDiagnostics")
+                .expect_contains(r#""diagnostics":[]"#),
+            ExpectedMessage::new(Some(1)).expect_contains(r#"\"rand: Integer is\""#),
+        ],
+    );
+}
+
+#[test]
+fn test_did_open_publishes_diagnostics() {
+    let mut env = Environment::new("common");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let url = Url::from_file_path(env.cache.abs_path(&Path::new("src").join("main.slang")))
+        .expect("couldn't convert file path to URL");
+
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+        notification::<notifications::DidOpen>(DidOpenTextDocumentParams {
+            text_document: TextDocumentItem::new(
+                url.clone(),
+                Some("slang".to_owned()),
+                Some(1),
+                "rand: Integer is\nend\nend\n".to_owned(),
+            ),
+        }).to_string(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    for _ in 0..2 {
+        assert_eq!(
+            ls_server::LsService::handle_message(&mut server),
+            ls_server::ServerStateChange::Continue
+        );
+    }
+    expect_messages(
+        results.clone(),
+        &[
+            ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+            ExpectedMessage::new(None)
+                .expect_contains("textDocument/publishDiagnostics")
+                .expect_contains("unexpected `end`"),
+        ],
+    );
+}
+
+#[test]
+fn test_did_change_watched_files() {
+    let mut env = Environment::new("common");
+
+    let source_file_path = Path::new("src").join("main.slang");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let url = Url::from_file_path(env.cache.abs_path(&source_file_path))
+        .expect("couldn't convert file path to URL");
+    let changed = DidChangeWatchedFilesParams {
+        changes: vec![FileEvent::new(url.clone(), FileChangeType::Changed)],
+    };
+
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned()))
+            .to_string(),
+        notification::<notifications::DidChangeWatchedFiles>(changed).to_string(),
+        notification::<notifications::DidOpen>(DidOpenTextDocumentParams {
+            text_document: TextDocumentItem::new(
+                url.clone(),
+                Some("slang".to_owned()),
+                Some(1),
+                "unsaved: Integer is\n".to_owned(),
+            ),
+        }).to_string(),
+        notification::<notifications::DidChangeWatchedFiles>(DidChangeWatchedFilesParams {
+            changes: vec![FileEvent::new(url.clone(), FileChangeType::Deleted)],
+        }).to_string(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    assert_eq!(
+        ls_server::LsService::handle_message(&mut server),
+        ls_server::ServerStateChange::Continue
+    );
+    expect_messages(
+        results.clone(),
+        &[
+            ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+        ],
+    );
+
+    // File on disk is re-analyzed.
+    assert_eq!(
+        ls_server::LsService::handle_message(&mut server),
+        ls_server::ServerStateChange::Continue
+    );
+    expect_messages(
+        results.clone(),
+        &[
+            ExpectedMessage::new(None)
+                .expect_contains("textDocument/publishDiagnostics")
+                .expect_contains(r#""diagnostics":[]"#),
+        ],
+    );
+
+    // Open buffer with unsaved edits is left alone.
+    assert_eq!(
+        ls_server::LsService::handle_message(&mut server),
+        ls_server::ServerStateChange::Continue
+    );
+    assert_eq!(
+        ls_server::LsService::handle_message(&mut server),
+        ls_server::ServerStateChange::Continue
+    );
+    expect_messages(results.clone(), &[]);
+}
+
+#[test]
+fn test_pull_configuration() {
+    let mut env = Environment::new("common");
+
+    let source_file_path = Path::new("src").join("main.slang");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let url = Url::from_file_path(env.cache.abs_path(&source_file_path))
+        .expect("couldn't convert file path to URL");
+    let changed = || {
+        notification::<notifications::DidChangeWatchedFiles>(DidChangeWatchedFilesParams {
+            changes: vec![FileEvent::new(url.clone(), FileChangeType::Changed)],
+        }).to_string()
+    };
+
+    let mut init = initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned()));
+    init.params.capabilities = json!({ "workspace": { "configuration": true } });
+
+    let messages = vec![
+        init.to_string(),
+        changed(),
+        notification::<notifications::DidChangeConfiguration>(DidChangeConfigurationParams {
+            settings: json::Value::Null,
+        }).to_string(),
+        // Answer to the `workspace/configuration` request, see `RecordOutput::provide_id`.
+        // The settings of the server come first, then those of the project.
+        json!({
+            "jsonrpc": "2.0",
+            "id": 0xDEADBEEFu32,
+            "result": [{}, { "diagnostics": false }],
+        }).to_string(),
+        changed(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    assert_eq!(
+        ls_server::LsService::handle_message(&mut server),
+        ls_server::ServerStateChange::Continue
+    );
+    expect_messages(
+        results.clone(),
+        &[
+            ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+        ],
+    );
+
+    assert_eq!(
+        ls_server::LsService::handle_message(&mut server),
+        ls_server::ServerStateChange::Continue
+    );
+    expect_messages(
+        results.clone(),
+        &[
+            ExpectedMessage::new(None).expect_contains("textDocument/publishDiagnostics"),
+        ],
+    );
+
+    // Disabling diagnostics clears the published ones.
+    assert_eq!(
+        ls_server::LsService::handle_message(&mut server),
+        ls_server::ServerStateChange::Continue
+    );
+    assert_eq!(
+        ls_server::LsService::handle_message(&mut server),
+        ls_server::ServerStateChange::Continue
+    );
+    expect_messages(
+        results.clone(),
+        &[
+            ExpectedMessage::new(None)
+                .expect_contains("textDocument/publishDiagnostics")
+                .expect_contains(r#""diagnostics":[]"#),
+        ],
+    );
+
+    assert_eq!(
+        ls_server::LsService::handle_message(&mut server),
+        ls_server::ServerStateChange::Continue
+    );
+    expect_messages(results.clone(), &[]);
+}
+
+#[test]
+fn test_initialize_workspace_folders() {
+    let mut env = Environment::new("common");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let folder = |path: &Path| WorkspaceFolder {
+        uri: Url::from_file_path(path).expect("couldn't convert file path to URL"),
+        name: path.file_name().unwrap().to_string_lossy().into_owned(),
+    };
+
+    let mut init = initialize(0, None);
+    init.params.workspace_folders = Some(vec![
+        folder(&root_path),
+        folder(&root_path.join("src")),
+    ]);
+
+    let change_folders = |added: Vec<WorkspaceFolder>, removed: Vec<WorkspaceFolder>| {
+        notification::<notifications::DidChangeWorkspaceFolders>(
+            DidChangeWorkspaceFoldersParams {
+                event: WorkspaceFoldersChangeEvent { added, removed },
+            },
+        ).to_string()
+    };
+
+    let messages = vec![
+        init.to_string(),
+        change_folders(vec![], vec![folder(&root_path.join("src"))]),
+        change_folders(vec![folder(&root_path.join("src"))], vec![]),
+    ];
+
+    let source_file_path = root_path.join("src").join("main.slang");
+    let manifest_path = root_path.join("Cargo.toml");
+
+    let (mut server, results) = env.mock_server(messages);
+    assert_eq!(
+        ls_server::LsService::handle_message(&mut server),
+        ls_server::ServerStateChange::Continue
+    );
+    expect_messages(
+        results.clone(),
+        &[
+            ExpectedMessage::new(Some(0)).expect_contains(
+                r#""workspaceFolders":{"changeNotifications":true,"supported":true}"#,
             ),
         ],
     );
+
+    let workspace = server.ctx.workspace().unwrap();
+    let root_of = |path: &Path| workspace.project_for(path).map(|p| p.root.clone());
+    assert_eq!(workspace.projects().len(), 2);
+    assert_eq!(root_of(&source_file_path), Some(root_path.join("src")));
+    assert_eq!(root_of(&manifest_path), Some(root_path.clone()));
+
+    assert_eq!(
+        ls_server::LsService::handle_message(&mut server),
+        ls_server::ServerStateChange::Continue
+    );
+    expect_messages(results.clone(), &[]);
+    assert_eq!(workspace.projects().len(), 1);
+    assert_eq!(root_of(&source_file_path), Some(root_path.clone()));
+
+    // The sources of an added folder are checked.
+    assert_eq!(
+        ls_server::LsService::handle_message(&mut server),
+        ls_server::ServerStateChange::Continue
+    );
+    expect_messages(
+        results.clone(),
+        &[
+            ExpectedMessage::new(None).expect_contains("textDocument/publishDiagnostics"),
+        ],
+    );
+    assert_eq!(workspace.projects().len(), 2);
+    assert_eq!(root_of(&source_file_path), Some(root_path.join("src")));
+}
+
+#[test]
+fn test_cancelled_request() {
+    let mut env = Environment::new("common");
+
+    let source_file_path = Path::new("src").join("main.slang");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let url = Url::from_file_path(env.cache.abs_path(&source_file_path))
+        .expect("couldn't convert file path to URL");
+
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+        request::<requests::SyntaxTree>(
+            1,
+            SyntaxTreeParams {
+                text_document: TextDocumentIdentifier::new(url.clone()),
+                range: None,
+            },
+        ).to_string(),
+        request::<requests::SyntaxTree>(
+            2,
+            SyntaxTreeParams {
+                text_document: TextDocumentIdentifier::new(url),
+                range: None,
+            },
+        ).to_string(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    server.state.pending.register(jsonrpc::Id::Num(1)).cancel();
+
+    for _ in 0..3 {
+        assert_eq!(
+            ls_server::LsService::handle_message(&mut server),
+            ls_server::ServerStateChange::Continue
+        );
+    }
+    // Both requests are handled concurrently, and answered in any order.
+    let responses = take_messages_by_id(results, 3);
+    assert!(responses[0]["result"]["capabilities"].is_object());
+    assert_eq!(responses[1]["error"]["code"], -32800);
+    assert!(responses[2]["result"].as_str().unwrap().starts_with("SOURCE_FILE"));
+}
+
+#[test]
+fn test_requests_see_snapshot() {
+    let mut env = Environment::new("common");
+
+    let source_file_path = Path::new("src").join("main.slang");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let url = Url::from_file_path(env.cache.abs_path(&source_file_path))
+        .expect("couldn't convert file path to URL");
+    let tree = |id| {
+        request::<requests::SyntaxTree>(
+            id,
+            SyntaxTreeParams {
+                text_document: TextDocumentIdentifier::new(url.clone()),
+                range: None,
+            },
+        ).to_string()
+    };
+
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+        notification::<notifications::DidOpen>(DidOpenTextDocumentParams {
+            text_document: TextDocumentItem::new(
+                url.clone(),
+                Some("slang".to_owned()),
+                Some(1),
+                "before: Integer is\nend before\n".to_owned(),
+            ),
+        }).to_string(),
+        tree(1),
+        notification::<notifications::DidChange>(DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier::new(url.clone(), 2),
+            content_changes: vec![
+                TextDocumentContentChangeEvent {
+                    range: None,
+                    range_length: None,
+                    text: "after: Integer is\nend after\n".to_owned(),
+                },
+            ],
+        }).to_string(),
+        tree(2),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    for _ in 0..5 {
+        assert_eq!(
+            ls_server::LsService::handle_message(&mut server),
+            ls_server::ServerStateChange::Continue
+        );
+    }
+
+    // Responses to concurrent requests may come in any order, the
+    // diagnostics following the change are published last.
+    let responses = take_messages_by_id(results, 4);
+    assert_eq!(responses[0]["method"], "textDocument/publishDiagnostics");
+    assert!(responses[2]["result"].as_str().unwrap().contains("\"before: Integer is\""));
+    assert!(responses[3]["result"].as_str().unwrap().contains("\"after: Integer is\""));
+}
+
+#[test]
+fn test_request_ids_are_echoed() {
+    let mut env = Environment::new("common");
+
+    let source_file_path = Path::new("src").join("main.slang");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let url = Url::from_file_path(env.cache.abs_path(&source_file_path))
+        .expect("couldn't convert file path to URL");
+    let tree = |id| {
+        Request::<requests::SyntaxTree> {
+            id,
+            params: SyntaxTreeParams {
+                text_document: TextDocumentIdentifier::new(url.clone()),
+                range: None,
+            },
+            _action: PhantomData,
+        }.to_string()
+    };
+
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+        tree(jsonrpc::Id::Str("4f3c2b1a-9d8e-4c7b-a6f5-e4d3c2b1a098".to_owned())),
+        tree(jsonrpc::Id::Num(u64::max_value())),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    assert_eq!(
+        ls_server::LsService::handle_message(&mut server),
+        ls_server::ServerStateChange::Continue
+    );
+    expect_messages(
+        results.clone(),
+        &[ExpectedMessage::new(Some(0)).expect_contains("capabilities")],
+    );
+
+    for _ in 0..2 {
+        assert_eq!(
+            ls_server::LsService::handle_message(&mut server),
+            ls_server::ServerStateChange::Continue
+        );
+    }
+    let mut ids: Vec<String> = take_messages_by_id(results, 2)
+        .into_iter()
+        .map(|response| response["id"].to_string())
+        .collect();
+    ids.sort();
+    assert_eq!(
+        ids,
+        vec![
+            r#""4f3c2b1a-9d8e-4c7b-a6f5-e4d3c2b1a098""#.to_owned(),
+            "18446744073709551615".to_owned(),
+        ]
+    );
+}
+
+#[test]
+fn test_every_request_is_answered() {
+    let mut env = Environment::new("common");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let url = Url::from_file_path(env.cache.abs_path(&Path::new("src").join("main.slang")))
+        .expect("couldn't convert file path to URL");
+    let tree = |id, url: Url| {
+        request::<requests::SyntaxTree>(
+            id,
+            SyntaxTreeParams {
+                text_document: TextDocumentIdentifier::new(url),
+                range: None,
+            },
+        ).to_string()
+    };
+
+    let messages = vec![
+        tree(1, url),
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+        tree(2, Url::parse("untitled:Untitled-1").unwrap()),
+        request::<ls_server::ShutdownRequest>(3, ls_server::NoParams).to_string(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    for _ in 0..4 {
+        assert_eq!(
+            ls_server::LsService::handle_message(&mut server),
+            ls_server::ServerStateChange::Continue
+        );
+    }
+
+    let responses = take_messages_by_id(results, 4);
+    assert!(responses[0]["result"]["capabilities"].is_object());
+    assert_eq!(responses[1]["error"]["code"], -32002);
+    assert_eq!(responses[2]["error"]["code"], -32602);
+    assert!(responses[3]["result"].is_null());
+    assert!(responses[3].get("error").is_none());
+}
+
+#[test]
+fn test_unknown_methods() {
+    let mut env = Environment::new("common");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let url = Url::from_file_path(env.cache.abs_path(&Path::new("src").join("main.slang")))
+        .expect("couldn't convert file path to URL");
+
+    let messages = vec![
+        notification::<notifications::DidOpen>(DidOpenTextDocumentParams {
+            text_document: TextDocumentItem::new(url, None, None, "end\n".to_owned()),
+        }).to_string(),
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+        r#"{"jsonrpc":"2.0","method":"$/setTraceNotification","params":{"value":"off"}}"#.to_owned(),
+        r#"{"jsonrpc":"2.0","method":"akkadia/unknown","params":{}}"#.to_owned(),
+        r#"{"jsonrpc":"2.0","id":1,"method":"textDocument/hover","params":{}}"#.to_owned(),
+        r#"{"jsonrpc":"2.0","id":2,"method":"$/unknown","params":{}}"#.to_owned(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    for _ in 0..6 {
+        assert_eq!(
+            ls_server::LsService::handle_message(&mut server),
+            ls_server::ServerStateChange::Continue
+        );
+    }
+    expect_messages(
+        results.clone(),
+        &[
+            ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+            ExpectedMessage::new(Some(1)).expect_contains(r#""code":-32601"#),
+            ExpectedMessage::new(Some(2)).expect_contains(r#""code":-32601"#),
+        ],
+    );
+}
+
+#[test]
+fn test_unknown_request_before_initialize() {
+    let mut env = Environment::new("common");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let messages = vec![
+        r#"{"jsonrpc":"2.0","id":1,"method":"textDocument/hover","params":{}}"#.to_owned(),
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    for _ in 0..2 {
+        assert_eq!(
+            ls_server::LsService::handle_message(&mut server),
+            ls_server::ServerStateChange::Continue
+        );
+    }
+    expect_messages(
+        results.clone(),
+        &[
+            ExpectedMessage::new(Some(1)).expect_contains(r#""code":-32002"#),
+            ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+        ],
+    );
+}
+
+#[test]
+fn test_initialize_twice() {
+    let mut env = Environment::exclusive("common");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let root_path = root_path.as_os_str().to_str().map(|x| x.to_owned());
+    let mut again = initialize(1, root_path.clone());
+    again.params.trace = TraceOption::Verbose;
+
+    let messages = vec![
+        initialize(0, root_path).to_string(),
+        again.to_string(),
+        r#"{"jsonrpc":"2.0","id":2,"method":"akkadia/afterInitialize","params":{}}"#.to_owned(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    let _forward = forward_logs(results.clone());
+    for _ in 0..3 {
+        assert_eq!(
+            ls_server::LsService::handle_message(&mut server),
+            ls_server::ServerStateChange::Continue
+        );
+    }
+
+    // The second `initialize` changes nothing, not even the trace.
+    assert!(logs_mentioning(&results, "akkadia/afterInitialize").is_empty());
+    let responses: Vec<json::Value> = results
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|result| json::from_str::<json::Value>(result).unwrap())
+        .filter(|value| value.get("id").is_some())
+        .collect();
+    assert_eq!(responses.len(), 3);
+    assert!(responses[0]["result"]["capabilities"].is_object());
+    assert_eq!(
+        responses[1]["error"],
+        json!({ "code": -32600, "message": "server already initialized" })
+    );
+    assert_eq!(responses[2]["error"]["code"], -32601);
+}
+
+#[test]
+fn test_recover_from_invalid_json() {
+    let mut env = Environment::new("common");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let messages = vec![
+        r#"{"jsonrpc":"2.0","id":1,"#.to_owned(),
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    for _ in 0..2 {
+        assert_eq!(
+            ls_server::LsService::handle_message(&mut server),
+            ls_server::ServerStateChange::Continue
+        );
+    }
+    expect_messages(
+        results.clone(),
+        &[
+            ExpectedMessage::new(None).expect_contains(r#""code":-32700"#),
+            ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+        ],
+    );
+}
+
+#[test]
+fn test_indexing_progress() {
+    let mut env = Environment::new("common");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let mut init = initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned()));
+    init.params.capabilities = json!({ "window": { "workDoneProgress": true } });
+
+    let messages = vec![
+        init.to_string(),
+        notification::<notifications::Initialized>(ls_server::NoParams).to_string(),
+        // Answer to `window/workDoneProgress/create`.
+        r#"{"jsonrpc":"2.0","id":3735928559,"result":null}"#.to_owned(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    for _ in 0..3 {
+        assert_eq!(
+            ls_server::LsService::handle_message(&mut server),
+            ls_server::ServerStateChange::Continue
+        );
+    }
+    expect_messages(
+        results.clone(),
+        &[
+            ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+            ExpectedMessage::new(None)
+                .expect_contains(r#""method":"$/progress""#)
+                .expect_contains(r#""kind":"begin""#)
+                .expect_contains(r#""title":"Indexing""#),
+            ExpectedMessage::new(None)
+                .expect_contains("textDocument/publishDiagnostics")
+                .expect_contains("main.slang"),
+            ExpectedMessage::new(None)
+                .expect_contains(r#""kind":"report""#)
+                .expect_contains(r#""percentage":100"#),
+            ExpectedMessage::new(None)
+                .expect_contains(r#""kind":"end""#)
+                .expect_contains("Checked 1 files"),
+        ],
+    );
+}
+
+#[test]
+fn test_no_progress_after_shutdown() {
+    let mut env = Environment::new("common");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let mut init = initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned()));
+    init.params.capabilities = json!({ "window": { "workDoneProgress": true } });
+
+    let messages = vec![
+        init.to_string(),
+        notification::<notifications::Initialized>(ls_server::NoParams).to_string(),
+        request::<ls_server::ShutdownRequest>(1, ls_server::NoParams).to_string(),
+        // Late answer to `window/workDoneProgress/create`.
+        r#"{"jsonrpc":"2.0","id":3735928559,"result":null}"#.to_owned(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    for _ in 0..4 {
+        assert_eq!(
+            ls_server::LsService::handle_message(&mut server),
+            ls_server::ServerStateChange::Continue
+        );
+    }
+    expect_messages(
+        results.clone(),
+        &[
+            ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+            ExpectedMessage::new(Some(1)).expect_contains(r#""result":null"#),
+        ],
+    );
+}
+
+#[test]
+fn test_shutdown_and_exit() {
+    let mut env = Environment::new("common");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+        request::<ls_server::ShutdownRequest>(1, ls_server::NoParams).to_string(),
+        r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/hover","params":{}}"#.to_owned(),
+        notification::<ls_server::ExitNotification>(ls_server::NoParams).to_string(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    for _ in 0..3 {
+        assert_eq!(
+            ls_server::LsService::handle_message(&mut server),
+            ls_server::ServerStateChange::Continue
+        );
+    }
+    expect_messages(
+        results.clone(),
+        &[
+            ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+            ExpectedMessage::new(Some(1)).expect_contains(r#""result":null"#),
+            ExpectedMessage::new(Some(2)).expect_contains(r#""code":-32600"#),
+        ],
+    );
+
+    assert_eq!(
+        ls_server::LsService::handle_message(&mut server),
+        ls_server::ServerStateChange::Break
+    );
+    assert_eq!(server.exit_code(), 0);
+}
+
+#[test]
+fn test_exit_without_shutdown() {
+    let mut env = Environment::new("common");
+
+    let messages = vec![
+        notification::<ls_server::ExitNotification>(ls_server::NoParams).to_string(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    assert_eq!(
+        ls_server::LsService::handle_message(&mut server),
+        ls_server::ServerStateChange::Break
+    );
+    assert_eq!(server.exit_code(), 1);
+    expect_messages(results.clone(), &[]);
+}
+
+#[test]
+fn test_survive_panics() {
+    let mut env = Environment::new("common");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let url = Url::from_file_path(env.cache.abs_path(&Path::new("src").join("main.slang")))
+        .expect("couldn't convert file path to URL");
+
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+        notification::<notifications::DidOpen>(DidOpenTextDocumentParams {
+            text_document: TextDocumentItem::new(url.clone(), None, None, "end\n".to_owned()),
+        }).to_string(),
+        // Out of the document, committing it to the VFS fails.
+        notification::<notifications::DidChange>(DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier::new(url.clone(), 2),
+            content_changes: vec![TextDocumentContentChangeEvent {
+                range: Some(Range::new(Position::new(10, 0), Position::new(10, 1))),
+                range_length: Some(1),
+                text: String::new(),
+            }],
+        }).to_string(),
+        request::<requests::SyntaxTree>(
+            1,
+            SyntaxTreeParams {
+                text_document: TextDocumentIdentifier::new(url),
+                range: None,
+            },
+        ).to_string(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    for _ in 0..4 {
+        assert_eq!(
+            ls_server::LsService::handle_message(&mut server),
+            ls_server::ServerStateChange::Continue
+        );
+    }
+    expect_messages(
+        results.clone(),
+        &[
+            ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+            ExpectedMessage::new(Some(1)).expect_contains(r#""result":"SOURCE_FILE"#),
+        ],
+    );
+}
+
+#[test]
+fn test_server_status() {
+    let mut env = Environment::new("common");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let url = Url::from_file_path(env.cache.abs_path(&Path::new("src").join("main.slang")))
+        .expect("couldn't convert file path to URL");
+
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+        notification::<notifications::DidOpen>(DidOpenTextDocumentParams {
+            text_document: TextDocumentItem::new(url, None, None, "end\n".to_owned()),
+        }).to_string(),
+        request::<requests::ServerStatusRequest>(1, ls_server::NoParams).to_string(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    for _ in 0..3 {
+        assert_eq!(
+            ls_server::LsService::handle_message(&mut server),
+            ls_server::ServerStateChange::Continue
+        );
+    }
+    expect_messages(
+        results.clone(),
+        &[
+            ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+            ExpectedMessage::new(Some(1))
+                .expect_contains(r#""initialize":{"count":1"#)
+                .expect_contains(r#""vfs":{"files":1,"bytes":4}"#)
+                .expect_contains(r#""queueDepth":0"#),
+        ],
+    );
+}
+
+#[test]
+fn test_latency_from_read() {
+    let mut env = Environment::new("common");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+        request::<requests::ServerStatusRequest>(1, ls_server::NoParams).to_string(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    // Read ahead meanwhile, `initialize` waits in the queue.
+    thread::sleep(Duration::from_millis(300));
+    for _ in 0..2 {
+        assert_eq!(
+            ls_server::LsService::handle_message(&mut server),
+            ls_server::ServerStateChange::Continue
+        );
+    }
+
+    let responses = take_messages_by_id(results, 2);
+    let initialize = &responses[1]["result"]["requests"]["initialize"];
+    assert!(initialize["p50Ms"].as_f64().unwrap() >= 300.0, "{}", initialize);
+}
+
+#[test]
+fn test_dropped_requests_are_not_pending() {
+    let mut env = Environment::new("common");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+        // Malformed, dropped before it is dispatched.
+        r#"{"jsonrpc":"2.0","id":5,"method":"textDocument/completion","params":5}"#.to_owned(),
+        // A notification, never answered.
+        r#"{"jsonrpc":"2.0","id":6,"method":"$/setTrace","params":{"value":"off"}}"#.to_owned(),
+        request::<requests::ServerStatusRequest>(1, ls_server::NoParams).to_string(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    for _ in 0..4 {
+        assert_eq!(
+            ls_server::LsService::handle_message(&mut server),
+            ls_server::ServerStateChange::Continue
+        );
+    }
+    expect_messages(
+        results.clone(),
+        &[
+            ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+            ExpectedMessage::new(None).expect_contains(r#""code":-32600"#),
+            ExpectedMessage::new(Some(1)).expect_contains(r#""queueDepth":0"#),
+        ],
+    );
+}
+
+#[test]
+fn test_debounced_diagnostics() {
+    let mut env = Environment::new("common");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let url = Url::from_file_path(env.cache.abs_path(&Path::new("src").join("main.slang")))
+        .expect("couldn't convert file path to URL");
+    let change = |version, text: &str| {
+        notification::<notifications::DidChange>(DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier::new(url.clone(), version),
+            content_changes: vec![TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: text.to_owned(),
+            }],
+        }).to_string()
+    };
+
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+        notification::<notifications::DidOpen>(DidOpenTextDocumentParams {
+            text_document: TextDocumentItem::new(url.clone(), None, Some(1), String::new()),
+        }).to_string(),
+        change(2, "r"),
+        change(3, "rand: Integer is\n"),
+        change(4, "rand: Integer is\nend\nend\n"),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    for _ in 0..5 {
+        assert_eq!(
+            ls_server::LsService::handle_message(&mut server),
+            ls_server::ServerStateChange::Continue
+        );
+    }
+    // Only the last change is analyzed.
+    expect_messages(
+        results.clone(),
+        &[
+            ExpectedMessage::new(Some(0)).expect_contains("capabilities"),
+            ExpectedMessage::new(None)
+                .expect_contains("textDocument/publishDiagnostics")
+                .expect_contains("unexpected `end`"),
+        ],
+    );
+    thread::sleep(Duration::from_millis(500));
+    assert!(results.lock().unwrap().is_empty());
+}
+
+#[test]
+fn test_syntax_tree() {
+    let mut env = Environment::new("common");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let url = Url::from_file_path(env.cache.abs_path(&Path::new("src").join("main.slang")))
+        .expect("couldn't convert file path to URL");
+
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+        request::<requests::SyntaxTree>(
+            1,
+            SyntaxTreeParams {
+                text_document: TextDocumentIdentifier::new(url.clone()),
+                range: None,
+            },
+        ).to_string(),
+        request::<requests::SyntaxTree>(
+            2,
+            SyntaxTreeParams {
+                text_document: TextDocumentIdentifier::new(url),
+                range: Some(Range::new(Position::new(1, 2), Position::new(1, 4))),
+            },
+        ).to_string(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    for _ in 0..3 {
+        assert_eq!(
+            ls_server::LsService::handle_message(&mut server),
+            ls_server::ServerStateChange::Continue
+        );
+    }
+
+    let responses = take_messages_by_id(results, 3);
+    assert_eq!(
+        responses[1]["result"],
+        "SOURCE_FILE@0:0..2:8\n  \
+         BLOCK@0:0..2:8\n    \
+         HEADER@0:0..0:16 \"rand: Integer is\"\n    \
+         LINE@1:1..1:9 \"return 4\"\n    \
+         END@2:0..2:8 \"end rand\"\n"
+    );
+    assert_eq!(responses[2]["result"], "LINE@1:1..1:9 \"return 4\"\n");
+}
+
+/// Returns the messages sent to the client for the logs mentioning `marker`.
+fn logs_mentioning(results: &Arc<Mutex<Vec<String>>>, marker: &str) -> Vec<json::Value> {
+    results
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|result| json::from_str::<json::Value>(result).unwrap())
+        .filter(|value| {
+            let method = value["method"].as_str().unwrap_or("");
+            (method == "window/logMessage" || method == "$/logTrace")
+                && value["params"]["message"].as_str().unwrap().contains(marker)
+        })
+        .collect()
+}
+
+#[test]
+fn test_forward_log_messages() {
+    let mut env = Environment::exclusive("common");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let client_log_level = |level: &str| {
+        notification::<notifications::DidChangeConfiguration>(DidChangeConfigurationParams {
+            settings: json!({ "akkadia": { "clientLogLevel": level } }),
+        }).to_string()
+    };
+
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+        client_log_level("debug"),
+        r#"{"jsonrpc":"2.0","id":1,"method":"akkadia/loggedMethod","params":{}}"#.to_owned(),
+        client_log_level("info"),
+        r#"{"jsonrpc":"2.0","id":2,"method":"akkadia/quietMethod","params":{}}"#.to_owned(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    let _forward = forward_logs(results.clone());
+    for _ in 0..5 {
+        assert_eq!(
+            ls_server::LsService::handle_message(&mut server),
+            ls_server::ServerStateChange::Continue
+        );
+    }
+
+    // Debug records only, the exchange is traced at a lower level.
+    let logs = logs_mentioning(&results, "akkadia/loggedMethod");
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0]["method"], "window/logMessage");
+    assert_eq!(
+        logs[0]["params"],
+        json!({ "type": 4, "message": "Method not found: akkadia/loggedMethod" })
+    );
+    assert!(logs_mentioning(&results, "akkadia/quietMethod").is_empty());
+}
+
+#[test]
+fn test_set_trace() {
+    let mut env = Environment::exclusive("common");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let set_trace = |value| {
+        notification::<notifications::SetTrace>(SetTraceParams { value }).to_string()
+    };
+
+    let messages = vec![
+        initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned())).to_string(),
+        set_trace(TraceOption::Messages),
+        r#"{"jsonrpc":"2.0","id":1,"method":"akkadia/tracedMethod","params":{}}"#.to_owned(),
+        set_trace(TraceOption::Off),
+        r#"{"jsonrpc":"2.0","id":2,"method":"akkadia/untracedMethod","params":{}}"#.to_owned(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    let _forward = forward_logs(results.clone());
+    for _ in 0..5 {
+        assert_eq!(
+            ls_server::LsService::handle_message(&mut server),
+            ls_server::ServerStateChange::Continue
+        );
+    }
+
+    let logs = logs_mentioning(&results, "akkadia/tracedMethod");
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0]["method"], "$/logTrace");
+    assert_eq!(
+        logs[0]["params"],
+        json!({ "message": "Method not found: akkadia/tracedMethod" })
+    );
+    assert!(logs_mentioning(&results, "akkadia/untracedMethod").is_empty());
+}
+
+#[test]
+fn test_initialize_trace() {
+    let mut env = Environment::exclusive("common");
+
+    let root_path = env.cache.abs_path(Path::new("."));
+    let mut init = initialize(0, root_path.as_os_str().to_str().map(|x| x.to_owned()));
+    init.params.trace = TraceOption::Verbose;
+
+    let messages = vec![
+        init.to_string(),
+        r#"{"jsonrpc":"2.0","id":1,"method":"akkadia/tracedMethod","params":{}}"#.to_owned(),
+    ];
+
+    let (mut server, results) = env.mock_server(messages);
+    let _forward = forward_logs(results.clone());
+    for _ in 0..2 {
+        assert_eq!(
+            ls_server::LsService::handle_message(&mut server),
+            ls_server::ServerStateChange::Continue
+        );
+    }
+
+    // Nothing is traced before `initialize` is answered.
+    let first: json::Value = json::from_str(&results.lock().unwrap()[0]).unwrap();
+    assert_eq!(first["id"], 0);
+
+    let logs = logs_mentioning(&results, "Method not found: akkadia/tracedMethod");
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0]["method"], "$/logTrace");
+    assert!(logs[0]["params"]["verbose"].as_str().unwrap().ends_with("[DEBUG]"));
 }